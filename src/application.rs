@@ -1,13 +1,15 @@
 use std::ops::{Deref};
+use std::path::Path;
 use crate::camera::Camera;
 use crate::timer::{FrameTimer,Timer};
 use imgui_glfw_rs::imgui::Ui;
 use imgui_glfw_rs::imgui::ImString;
+use imgui_glfw_rs::imgui::im_str;
 use log::info;
 use crate::input::Input;
 use crate::opencl::OpenCL;
 use crate::profiler::Profiler;
-use crate::renderer::Renderer;
+use crate::renderer::{Renderer, RenderMode, ToneMapping, PixelFilter, SamplingStrategy};
 use crate::scene::Scene;
 
 pub struct Application
@@ -22,13 +24,13 @@ pub struct Application
 
 impl Application
 {
-    pub fn new() -> Self
+    pub fn new(environment_path: Option<&Path>) -> Self
     {
         let camera = Camera::new();
 
         let cl = OpenCL::init();
         let mut renderer = Renderer::new(&cl);
-        let mut scene = Scene::new(&cl);
+        let mut scene = Scene::new(&cl, environment_path);
 
         renderer.set_scene(&scene);
         renderer.set_camera(&camera);
@@ -75,6 +77,9 @@ impl Application
             let render_string = format!("ms: {}\nfps: {}\n", self.profiler.ms, self.profiler.fps);
 
             ui.text(ImString::new(render_string).deref());
+
+            self.render_settings_ui(ui);
+            self.bvh_stats_ui(ui);
         }
         else
         {
@@ -82,6 +87,74 @@ impl Application
         }
     }
 
+    fn render_settings_ui(&mut self, ui: &mut Ui)
+    {
+        let settings = &mut self.renderer.settings;
+
+        ui.separator();
+        ui.radio_button(im_str!("path tracing"), &mut settings.render_mode, RenderMode::PathTracing);
+        ui.radio_button(im_str!("accumulated light"), &mut settings.render_mode, RenderMode::AccumulatedLight);
+        ui.radio_button(im_str!("light layer"), &mut settings.render_mode, RenderMode::LightLayer);
+        ui.radio_button(im_str!("albedo"), &mut settings.render_mode, RenderMode::Albedo);
+        ui.radio_button(im_str!("normals"), &mut settings.render_mode, RenderMode::Normals);
+
+        ui.separator();
+        let mut samples_per_pixel = settings.samples_per_pixel() as i32;
+        ui.slider_int(im_str!("samples per pixel"), &mut samples_per_pixel, 1, 16).build();
+        settings.set_samples_per_pixel(samples_per_pixel as usize);
+
+        ui.radio_button(im_str!("box filter"), &mut settings.pixel_filter, PixelFilter::Box);
+        ui.radio_button(im_str!("tent filter"), &mut settings.pixel_filter, PixelFilter::Tent);
+        ui.radio_button(im_str!("blackman-harris filter"), &mut settings.pixel_filter, PixelFilter::BlackmanHarris);
+
+        ui.separator();
+        let mut num_bounces = settings.num_bounces as i32;
+        ui.slider_int(im_str!("bounces"), &mut num_bounces, 1, 32).build();
+        settings.num_bounces = num_bounces as usize;
+
+        let mut russian_roulette_start = settings.russian_roulette_start as i32;
+        ui.slider_int(im_str!("roulette start"), &mut russian_roulette_start, 0, settings.num_bounces as i32).build();
+        settings.russian_roulette_start = russian_roulette_start as usize;
+        ui.slider_float(im_str!("roulette max survival"), &mut settings.russian_roulette_max_survival, 0.05, 1.0).build();
+
+        ui.radio_button(im_str!("multiple importance sampling"), &mut settings.sampling_strategy, SamplingStrategy::MultipleImportance);
+        ui.radio_button(im_str!("light sampling only"), &mut settings.sampling_strategy, SamplingStrategy::Light);
+        ui.radio_button(im_str!("bsdf sampling only"), &mut settings.sampling_strategy, SamplingStrategy::Bsdf);
+
+        if settings.render_mode == RenderMode::LightLayer
+        {
+            let mut light_layer = settings.light_layer as i32;
+            ui.slider_int(im_str!("layer"), &mut light_layer, 0, settings.num_bounces as i32).build();
+            settings.light_layer = light_layer as usize;
+        }
+
+        ui.separator();
+        ui.slider_float(im_str!("environment rotation"), &mut settings.environment_rotation, 0.0, std::f32::consts::PI * 2.0).build();
+        ui.slider_float(im_str!("environment intensity"), &mut settings.environment_intensity, 0.0, 10.0).build();
+
+        ui.separator();
+        ui.radio_button(im_str!("linear"), &mut settings.tone_mapping, ToneMapping::Linear);
+        ui.radio_button(im_str!("reinhard"), &mut settings.tone_mapping, ToneMapping::Reinhard);
+        ui.radio_button(im_str!("aces"), &mut settings.tone_mapping, ToneMapping::Aces);
+        ui.radio_button(im_str!("agx"), &mut settings.tone_mapping, ToneMapping::AgX);
+        ui.slider_float(im_str!("exposure"), &mut settings.exposure, -10.0, 10.0).build();
+    }
+
+    fn bvh_stats_ui(&self, ui: &mut Ui)
+    {
+        ui.separator();
+        if !ui.collapsing_header(im_str!("bvh statistics")).build()
+        {
+            return;
+        }
+
+        for (mesh_idx, bvh) in self.scene.bvhs.iter().enumerate()
+        {
+            let stats_string = format!("mesh {}\n{}", mesh_idx, bvh.stats);
+            ui.text(ImString::new(stats_string).deref());
+        }
+    }
+
     pub fn shutdown(&mut self)
     {
         info!("Application shut down");
@@ -0,0 +1,436 @@
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::bvh_construction::{BVHBuildOptions, BVHSplitMode, BVHStats};
+use crate::math::*;
+use crate::render_components::*;
+
+// bvh_cache.rs
+// Contains the on-disk cache of built bvhs, so large scenes do not have to be rebuilt on every launch
+// a cache file is named after a hash of the triangles and the build options,
+// so changing either of them misses the cache and builds a new bvh
+// the file of the old version stays behind until prune removes the files a scene did not use
+
+const BVH_CACHE_MAGIC: [u8; 4] = *b"BVHC";
+
+// older files are rebuilt when this differs, increment it whenever one of these changes:
+// - the splitting in BVHConstructor (subdivide, build_subtree, partition and the split plane searches)
+//   or the reordering of the references in finalize_sbvh
+// - the meaning of a field of BVHBuildOptions, new fields also have to be added to cache_key
+// - the layout written by store and read by load
+// changes to the size of BVHBuildOptions, BVHNode or Triangle already miss the cache through cache_key
+const BVH_CACHE_VERSION: u32 = 1;
+
+// magic, version, key, triangle count, spatial splits, node count and reference count
+const BVH_CACHE_HEADER_SIZE: usize = 4 + 4 + 8 * 5;
+
+// min bound, max bound, triangle count and left first
+const BVH_CACHE_NODE_SIZE: usize = 6 * 4 + 2 * 4;
+
+// parallel builds of the same mesh write to their own temporary file before it is moved into place
+static TMP_FILE_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+// load the bvh of a mesh from the cache, or build it and store it in the cache,
+// also returns whether the bvh was loaded from the cache and the key of its cache file
+pub fn load_or_build(cache_dir: &Path, triangles: &Vec<Triangle>, options: &BVHBuildOptions) -> (BVH, bool, u64)
+{
+    let key = cache_key(triangles, options);
+
+    // nothing to gain for empty meshes, their root is not a valid node either
+    if triangles.is_empty()
+    {
+        return (BVH::from_mesh(triangles, &compute_bounds_from_triangles(triangles), options), false, key);
+    }
+
+    let path = cache_path(cache_dir, key);
+
+    if let Some(bvh) = load(&path, key, triangles.len())
+    {
+        return (bvh, true, key);
+    }
+
+    let bvh = BVH::from_mesh(triangles, &compute_bounds_from_triangles(triangles), options);
+    if let Err(error) = store(cache_dir, &path, key, triangles.len(), &bvh)
+    {
+        warn!("could not store bvh in {}: {}", path.display(), error);
+    }
+    return (bvh, false, key);
+}
+
+// remove the cache files whose key is not in keys, otherwise every edit of a mesh or of its options
+// leaves another file behind, temporary files are kept because another build may still be writing them
+pub fn prune(cache_dir: &Path, keys: &[u64])
+{
+    let entries = match std::fs::read_dir(cache_dir)
+    {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+
+    let mut removed = 0;
+    for entry in entries.flatten()
+    {
+        let path = entry.path();
+        if path.extension().map_or(true, |extension| extension != "bvh")
+        {
+            continue;
+        }
+        if keys.iter().any(|key| cache_path(cache_dir, *key) == path)
+        {
+            continue;
+        }
+        match std::fs::remove_file(&path)
+        {
+            Ok(_) => removed += 1,
+            Err(error) => warn!("could not remove unused bvh {}: {}", path.display(), error)
+        }
+    }
+
+    if removed > 0
+    {
+        info!("removed {} unused bvhs from {}", removed, cache_dir.display());
+    }
+}
+
+fn cache_path(cache_dir: &Path, key: u64) -> PathBuf
+{
+    return cache_dir.join(format!("{:016x}.bvh", key));
+}
+
+// 64 bit fnv-1a, unlike the hasher of the standard library it stays the same between compiler versions
+fn hash_bytes(hash: u64, bytes: &[u8]) -> u64
+{
+    let mut hash = hash;
+    for byte in bytes
+    {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+fn cache_key(triangles: &Vec<Triangle>, options: &BVHBuildOptions) -> u64
+{
+    let mut hash: u64 = 0xcbf29ce484222325;
+    hash = hash_bytes(hash, &BVH_CACHE_VERSION.to_le_bytes());
+    hash = hash_bytes(hash, &(std::mem::size_of::<BVHBuildOptions>() as u64).to_le_bytes());
+    hash = hash_bytes(hash, &(std::mem::size_of::<BVHNode>() as u64).to_le_bytes());
+    hash = hash_bytes(hash, &(std::mem::size_of::<Triangle>() as u64).to_le_bytes());
+    hash = hash_bytes(hash, &(triangles.len() as u64).to_le_bytes());
+    for triangle in triangles
+    {
+        for vertex in [triangle.vertex0, triangle.vertex1, triangle.vertex2]
+        {
+            hash = hash_bytes(hash, &vertex.x.to_le_bytes());
+            hash = hash_bytes(hash, &vertex.y.to_le_bytes());
+            hash = hash_bytes(hash, &vertex.z.to_le_bytes());
+        }
+        hash = hash_bytes(hash, &triangle.tri_idx.to_le_bytes());
+    }
+
+    let split_mode: u8 = if options.split_mode == BVHSplitMode::Spatial { 1 } else { 0 };
+    hash = hash_bytes(hash, &[split_mode]);
+    hash = hash_bytes(hash, &(options.bins as u64).to_le_bytes());
+    hash = hash_bytes(hash, &options.spatial_alpha.to_le_bytes());
+    hash = hash_bytes(hash, &(options.max_leaf_size as u64).to_le_bytes());
+    hash = hash_bytes(hash, &options.slack_budget.to_le_bytes());
+    hash = hash_bytes(hash, &options.traversal_cost.to_le_bytes());
+    hash = hash_bytes(hash, &options.intersection_cost.to_le_bytes());
+    return hash;
+}
+
+fn store(cache_dir: &Path, path: &Path, key: u64, triangle_count: usize, bvh: &BVH) -> std::io::Result<()>
+{
+    let mut bytes: Vec<u8> = Vec::with_capacity(BVH_CACHE_HEADER_SIZE + bvh.bvh_nodes.len() * BVH_CACHE_NODE_SIZE + bvh.triangle_idx.len() * 4);
+    bytes.extend_from_slice(&BVH_CACHE_MAGIC);
+    bytes.extend_from_slice(&BVH_CACHE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&key.to_le_bytes());
+    bytes.extend_from_slice(&(triangle_count as u64).to_le_bytes());
+    bytes.extend_from_slice(&(bvh.stats.spatial_splits as u64).to_le_bytes());
+    bytes.extend_from_slice(&(bvh.bvh_nodes.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&(bvh.triangle_idx.len() as u64).to_le_bytes());
+
+    for node in &bvh.bvh_nodes
+    {
+        for value in [node.bounds.min_bound.x, node.bounds.min_bound.y, node.bounds.min_bound.z, node.bounds.max_bound.x, node.bounds.max_bound.y, node.bounds.max_bound.z]
+        {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(node.tri_count as u32).to_le_bytes());
+        bytes.extend_from_slice(&(node.left_first as u32).to_le_bytes());
+    }
+
+    for idx in &bvh.triangle_idx
+    {
+        bytes.extend_from_slice(&(*idx as u32).to_le_bytes());
+    }
+
+    // moving the finished file into place keeps a crash or a parallel build from leaving a partial file behind
+    std::fs::create_dir_all(cache_dir)?;
+    let tmp_path = path.with_extension(format!("{}.{}.tmp", std::process::id(), TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let result = std::fs::write(&tmp_path, &bytes).and_then(|_| std::fs::rename(&tmp_path, path));
+    if result.is_err()
+    {
+        // a failed write or move must not leave the temporary file behind
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    return result;
+}
+
+// reads little endian values from a cache file, every read fails once the file runs out
+struct CacheReader<'a>
+{
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> CacheReader<'a>
+{
+    fn read<const N: usize>(&mut self) -> Option<[u8; N]>
+    {
+        let value = self.bytes.get(self.pos..self.pos + N)?.try_into().ok()?;
+        self.pos += N;
+        return Some(value);
+    }
+
+    fn read_u32(&mut self) -> Option<u32>
+    {
+        return Some(u32::from_le_bytes(self.read()?));
+    }
+
+    fn read_u64(&mut self) -> Option<u64>
+    {
+        return Some(u64::from_le_bytes(self.read()?));
+    }
+
+    fn read_f32(&mut self) -> Option<f32>
+    {
+        return Some(f32::from_le_bytes(self.read()?));
+    }
+
+    fn read_float3(&mut self) -> Option<Float3>
+    {
+        return Some(Float3::from_xyz(self.read_f32()?, self.read_f32()?, self.read_f32()?));
+    }
+}
+
+// a missing, outdated or damaged file is not an error, the bvh is simply built again
+fn load(path: &Path, key: u64, triangle_count: usize) -> Option<BVH>
+{
+    let bytes = std::fs::read(path).ok()?;
+    let mut reader = CacheReader{ bytes: &bytes, pos: 0 };
+
+    if reader.read::<4>()? != BVH_CACHE_MAGIC || reader.read_u32()? != BVH_CACHE_VERSION || reader.read_u64()? != key
+    {
+        return None;
+    }
+    if reader.read_u64()? != triangle_count as u64
+    {
+        return None;
+    }
+
+    let spatial_splits = reader.read_u64()? as usize;
+    let node_count = reader.read_u64()? as usize;
+    let reference_count = reader.read_u64()? as usize;
+    let file_size = node_count.checked_mul(BVH_CACHE_NODE_SIZE)?.checked_add(reference_count.checked_mul(4)?)?.checked_add(BVH_CACHE_HEADER_SIZE)?;
+    if node_count == 0 || bytes.len() != file_size
+    {
+        return None;
+    }
+
+    let mut bvh_nodes: Vec<BVHNode> = Vec::with_capacity(node_count);
+    for _ in 0..node_count
+    {
+        let min_bound = reader.read_float3()?;
+        let max_bound = reader.read_float3()?;
+        bvh_nodes.push(BVHNode{
+            bounds: AABB::from_bounds(&min_bound, &max_bound),
+            tri_count: reader.read_u32()? as usize,
+            left_first: reader.read_u32()? as usize
+        });
+    }
+
+    let mut triangle_idx: Vec<usize> = Vec::with_capacity(reference_count);
+    for _ in 0..reference_count
+    {
+        triangle_idx.push(reader.read_u32()? as usize);
+    }
+
+    // the scene indexes the triangles with these, so they have to stay in range even when the file is damaged,
+    // children are always stored after their parent which also rules out cycles
+    let mut stack: Vec<usize> = vec![0];
+    while let Some(node_idx) = stack.pop()
+    {
+        let node = &bvh_nodes[node_idx];
+        if node.is_leaf()
+        {
+            if node.left_first + node.tri_count > reference_count
+            {
+                return None;
+            }
+            continue;
+        }
+        if node.left_first <= node_idx || node.left_first + 1 >= node_count
+        {
+            return None;
+        }
+        stack.push(node.left_first);
+        stack.push(node.left_first + 1);
+    }
+    if triangle_idx.iter().any(|idx| *idx >= triangle_count)
+    {
+        return None;
+    }
+
+    let stats = BVHStats::from_nodes(&bvh_nodes, triangle_count, spatial_splits);
+    return Some(BVH{
+        bvh_nodes,
+        triangle_idx,
+        stats
+    });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::bvh_construction::tests::test_triangles;
+
+    fn test_dir(name: &str) -> PathBuf
+    {
+        return std::env::temp_dir().join(format!("bvh_cache_{}_{}", name, std::process::id()));
+    }
+
+    #[test]
+    fn stored_bvh_loads_back()
+    {
+        let dir = test_dir("round_trip");
+        let path = dir.join("test.bvh");
+        let triangles = test_triangles(400);
+        let options = BVHBuildOptions::spatial();
+        let key = cache_key(&triangles, &options);
+
+        let bvh = BVH::from_mesh(&triangles, &compute_bounds_from_triangles(&triangles), &options);
+        store(&dir, &path, key, triangles.len(), &bvh).unwrap();
+        let loaded = load(&path, key, triangles.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let loaded = loaded.expect("the stored bvh should load");
+        assert_eq!(loaded.bvh_nodes, bvh.bvh_nodes);
+        assert_eq!(loaded.triangle_idx, bvh.triangle_idx);
+        assert_eq!(loaded.stats.spatial_splits, bvh.stats.spatial_splits);
+    }
+
+    #[test]
+    fn mismatched_key_is_rejected()
+    {
+        let dir = test_dir("key");
+        let path = dir.join("test.bvh");
+        let triangles = test_triangles(400);
+        let options = BVHBuildOptions::new();
+        let key = cache_key(&triangles, &options);
+        assert_ne!(key, cache_key(&triangles, &BVHBuildOptions::spatial()));
+
+        let bvh = BVH::from_mesh(&triangles, &compute_bounds_from_triangles(&triangles), &options);
+        store(&dir, &path, key, triangles.len(), &bvh).unwrap();
+        let loaded = load(&path, key + 1, triangles.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(loaded.is_none());
+    }
+
+    #[test]
+    fn prune_removes_unused_files()
+    {
+        let dir = test_dir("prune");
+        let triangles = test_triangles(400);
+        let (_, _, used_key) = load_or_build(&dir, &triangles, &BVHBuildOptions::new());
+        let (_, _, unused_key) = load_or_build(&dir, &triangles, &BVHBuildOptions::spatial());
+        let tmp_path = dir.join("other.tmp");
+        std::fs::write(&tmp_path, [0u8]).unwrap();
+
+        prune(&dir, &[used_key]);
+        let used_exists = cache_path(&dir, used_key).exists();
+        let unused_exists = cache_path(&dir, unused_key).exists();
+        let tmp_exists = tmp_path.exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(used_exists);
+        assert!(!unused_exists);
+        assert!(tmp_exists);
+    }
+
+    // store a valid bvh, let damage change the bytes of the file and load it again
+    fn load_damaged(name: &str, damage: impl Fn(&BVH, &mut Vec<u8>)) -> Option<BVH>
+    {
+        let dir = test_dir(name);
+        let path = dir.join("test.bvh");
+        let triangles = test_triangles(400);
+        let options = BVHBuildOptions::new();
+        let key = cache_key(&triangles, &options);
+
+        let bvh = BVH::from_mesh(&triangles, &compute_bounds_from_triangles(&triangles), &options);
+        store(&dir, &path, key, triangles.len(), &bvh).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        damage(&bvh, &mut bytes);
+        std::fs::write(&path, &bytes).unwrap();
+        let loaded = load(&path, key, triangles.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+        return loaded;
+    }
+
+    // the triangle count and left first of a node follow its bounds
+    fn write_node(bytes: &mut Vec<u8>, node_idx: usize, tri_count: usize, left_first: usize)
+    {
+        let offset = BVH_CACHE_HEADER_SIZE + node_idx * BVH_CACHE_NODE_SIZE + 6 * 4;
+        bytes[offset..offset + 4].copy_from_slice(&(tri_count as u32).to_le_bytes());
+        bytes[offset + 4..offset + 8].copy_from_slice(&(left_first as u32).to_le_bytes());
+    }
+
+    // the leftmost leaf, unlike a node found by index it is always reachable from the root
+    fn first_leaf(bvh: &BVH) -> usize
+    {
+        let mut node_idx = 0;
+        while !bvh.bvh_nodes[node_idx].is_leaf()
+        {
+            node_idx = bvh.bvh_nodes[node_idx].left_first;
+        }
+        return node_idx;
+    }
+
+    #[test]
+    fn truncated_file_is_rejected()
+    {
+        assert!(load_damaged("truncated", |_, bytes| { bytes.pop(); }).is_none());
+    }
+
+    #[test]
+    fn child_before_its_parent_is_rejected()
+    {
+        assert!(load_damaged("backwards", |_, bytes| write_node(bytes, 0, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn child_past_the_nodes_is_rejected()
+    {
+        assert!(load_damaged("past_nodes", |bvh, bytes| write_node(bytes, 0, 0, bvh.bvh_nodes.len() - 1)).is_none());
+    }
+
+    #[test]
+    fn leaf_past_the_references_is_rejected()
+    {
+        assert!(load_damaged("past_references", |bvh, bytes| {
+            let leaf = first_leaf(bvh);
+            write_node(bytes, leaf, bvh.bvh_nodes[leaf].tri_count, bvh.triangle_idx.len());
+        }).is_none());
+    }
+
+    #[test]
+    fn triangle_index_past_the_triangles_is_rejected()
+    {
+        assert!(load_damaged("past_triangles", |bvh, bytes| {
+            let offset = bytes.len() - 4;
+            bytes[offset..].copy_from_slice(&(bvh.triangle_idx.len() as u32).to_le_bytes());
+        }).is_none());
+    }
+}
@@ -1,5 +1,14 @@
 use crate::math::{EPSILON, Float3};
 use crate::render_components::{AABB, BVH, BVHNode, Triangle};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// nodes with at least this many triangles bin them in parallel
+const PARALLEL_BINNING_THRESHOLD: usize = 4096;
+
+// nodes with at least this many triangles build their children in parallel, smaller subtrees stay on one thread
+const PARALLEL_SUBTREE_THRESHOLD: usize = 1024;
 
 struct Clipped
 {
@@ -131,6 +140,197 @@ impl Clipped
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum BVHSplitMode
+{
+    // triangles are only partitioned by their centroids
+    Object,
+
+    // triangles that straddle a split plane may be clipped and referenced on both sides (sbvh),
+    // this helps meshes with long thin triangles at the cost of a longer build
+    Spatial
+}
+
+#[derive(Clone, Copy)]
+pub struct BVHBuildOptions
+{
+    pub split_mode: BVHSplitMode,
+
+    // number of split plane candidates per axis is one less than this
+    pub bins: usize,
+
+    // spatial splits are only tried when the children of the best object split overlap by more than
+    // this fraction of the surface area of the root
+    pub spatial_alpha: f32,
+
+    // nodes with more triangles are split even when the sah prefers a leaf, this is best effort,
+    // nodes whose triangle centers all coincide cannot be split and stay larger leaves
+    pub max_leaf_size: usize,
+
+    // number of triangle references spatial splits may add, as a fraction of the triangle count
+    pub slack_budget: f32,
+
+    // sah cost of visiting a node and of intersecting a single triangle
+    pub traversal_cost: f32,
+    pub intersection_cost: f32
+}
+
+impl BVHBuildOptions
+{
+    // object splits only, this is what every mesh uses unless told otherwise
+    pub fn new() -> Self
+    {
+        BVHBuildOptions{
+            split_mode: BVHSplitMode::Object,
+            bins: 4,
+            spatial_alpha: 1e-5,
+            max_leaf_size: usize::MAX,
+            slack_budget: 0.0,
+            traversal_cost: 0.0,
+            intersection_cost: 1.0
+        }
+    }
+
+    pub fn spatial() -> Self
+    {
+        BVHBuildOptions{
+            split_mode: BVHSplitMode::Spatial,
+            bins: 8,
+            slack_budget: 0.3,
+            ..BVHBuildOptions::new()
+        }
+    }
+}
+
+// quality of a built bvh, the sah cost uses unit traversal and intersection costs
+// so it can be compared between builds with different options
+#[derive(Clone)]
+pub struct BVHStats
+{
+    // sah cost relative to the surface area of the root
+    pub sah_cost: f32,
+    pub node_count: u32,
+    pub leaf_count: u32,
+    pub spatial_splits: usize,
+    pub max_depth: u32,
+
+    // average depth of the leaves
+    pub average_depth: f32,
+
+    // number of leaves for every triangle count that occurs, stored sparsely since a single large leaf
+    // would otherwise need a bucket for every smaller size
+    pub leaf_sizes: BTreeMap<u32, u32>,
+
+    // triangle references in the leaves per triangle of the mesh, above one when spatial splits duplicated triangles
+    pub duplication_factor: f32
+}
+
+impl fmt::Display for BVHStats
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "sah cost: {:.2}", self.sah_cost)?;
+        writeln!(f, "nodes: {}, leaves: {}", self.node_count, self.leaf_count)?;
+        writeln!(f, "spatial splits: {}, duplication: {:.3}", self.spatial_splits, self.duplication_factor)?;
+        writeln!(f, "depth: max {}, average {:.1}", self.max_depth, self.average_depth)?;
+
+        let leaf_sizes: Vec<String> = self.leaf_sizes.iter()
+            .map(|(size, count)| format!("{}: {}", size, count))
+            .collect();
+        return write!(f, "leaf sizes: {}", leaf_sizes.join(", "));
+    }
+}
+
+impl BVHStats
+{
+    // the number of spatial splits can not be told from the nodes, it is passed along from the build
+    pub fn from_nodes(bvh_nodes: &[BVHNode], prim_count: usize, spatial_splits: usize) -> Self
+    {
+        let mut stats = BVHStats{
+            sah_cost: 0.0,
+            node_count: 0,
+            leaf_count: 0,
+            spatial_splits,
+            max_depth: 0,
+            average_depth: 0.0,
+            leaf_sizes: BTreeMap::new(),
+            duplication_factor: 0.0
+        };
+
+        // the root of an empty mesh is neither a leaf nor does it have children
+        if prim_count == 0
+        {
+            return stats;
+        }
+
+        stats.sah_cost = BVHStats::sah_cost(bvh_nodes, 0);
+        stats.node_count = BVHStats::get_node_count(bvh_nodes, 0);
+        stats.leaf_count = BVHStats::get_leaf_count(bvh_nodes, 0);
+
+        let mut depth_sum: u32 = 0;
+        let mut reference_count: u32 = 0;
+        BVHStats::collect_leaf_stats(bvh_nodes, 0, 0, &mut stats, &mut depth_sum, &mut reference_count);
+        stats.average_depth = depth_sum as f32 / stats.leaf_count as f32;
+        stats.duplication_factor = reference_count as f32 / prim_count as f32;
+
+        return stats;
+    }
+
+    fn collect_leaf_stats(bvh_nodes: &[BVHNode], node_idx: usize, depth: u32, stats: &mut BVHStats, depth_sum: &mut u32, reference_count: &mut u32)
+    {
+        let node = &bvh_nodes[node_idx];
+        if node.is_leaf()
+        {
+            stats.max_depth = stats.max_depth.max(depth);
+            *depth_sum += depth;
+            *reference_count += node.tri_count as u32;
+            *stats.leaf_sizes.entry(node.tri_count as u32).or_insert(0) += 1;
+            return;
+        }
+        BVHStats::collect_leaf_stats(bvh_nodes, node.left_first, depth + 1, stats, depth_sum, reference_count);
+        BVHStats::collect_leaf_stats(bvh_nodes, node.left_first + 1, depth + 1, stats, depth_sum, reference_count);
+    }
+
+    fn get_node_count(bvh_nodes: &[BVHNode], node_idx: usize) -> u32
+    {
+        let node = &bvh_nodes[node_idx];
+        if node.is_leaf()
+        {
+            return 1;
+        }
+        return BVHStats::get_node_count(bvh_nodes, node.left_first) + BVHStats::get_node_count(bvh_nodes, node.left_first + 1) + 1;
+    }
+
+    fn get_leaf_count(bvh_nodes: &[BVHNode], node_idx: usize) -> u32
+    {
+        let node = &bvh_nodes[node_idx];
+        if node.is_leaf()
+        {
+            return 1;
+        }
+        return BVHStats::get_leaf_count(bvh_nodes, node.left_first) + BVHStats::get_leaf_count(bvh_nodes, node.left_first + 1);
+    }
+
+    fn sah_cost(bvh_nodes: &[BVHNode], node_idx: usize) -> f32
+    {
+        let node = &bvh_nodes[node_idx];
+        let area = node.bounds.area();
+        if node.is_leaf()
+        {
+            return area * (node.tri_count as f32);
+        }
+        let mut cost: f32 = 0.0;
+        cost += BVHStats::sah_cost(bvh_nodes, node.left_first);
+        cost += BVHStats::sah_cost(bvh_nodes, node.left_first + 1);
+        cost += area;
+        if node_idx == 0
+        {
+            cost *= 1.0 / area;
+        }
+        return cost;
+    }
+}
+
 pub struct BVHConstructor
 {
     pub triangles: Vec<BVHTriangle>,
@@ -141,18 +341,26 @@ pub struct BVHConstructor
     pub nodes_used: usize,
     pub triangle_ptr: usize,
     pub spatial_splits: usize,
-    pub is_spatial: bool,
-    pub bin_size: usize
+    pub prim_count: usize,
+    pub options: BVHBuildOptions
 }
 
 
 impl BVHConstructor
 {
-    pub fn from_mesh(triangles: &Vec<Triangle>, bounds: &AABB, bin_size: usize) -> Self
+    pub fn from_mesh(triangles: &Vec<Triangle>, bounds: &AABB, options: &BVHBuildOptions) -> Self
     {
+        // a single bin has no split plane and would turn the whole mesh into one leaf
+        assert!(options.bins >= 2, "BVH build needs at least 2 bins, got {}", options.bins);
+
         let prim_count = triangles.len();
-        let mut bvh_triangles: Vec<BVHTriangle> = Vec::with_capacity(prim_count);
-        let mut triangle_idx: Vec<usize> = Vec::with_capacity(prim_count);
+
+        // spatial splits duplicate triangles into the slack that is left between the children of a node
+        let slack = if options.split_mode == BVHSplitMode::Spatial { (prim_count as f32 * options.slack_budget.max(0.0)) as usize } else { 0 };
+        let reference_count = prim_count + slack;
+
+        let mut bvh_triangles: Vec<BVHTriangle> = Vec::with_capacity(reference_count);
+        let mut triangle_idx: Vec<usize> = Vec::with_capacity(reference_count);
 
         let mut tri_idx = 0;
         for triangle in triangles
@@ -171,8 +379,15 @@ impl BVHConstructor
             tri_idx += 1;
         }
 
+        // the duplicates are appended while splitting, the unused slack keeps pointing at the first triangle
+        if prim_count > 0
+        {
+            bvh_triangles.resize(reference_count, bvh_triangles[0]);
+        }
+        triangle_idx.resize(reference_count, 0);
+
         // build bvh
-        let mut bvh_nodes: Vec<BVHNode> = Vec::with_capacity(prim_count * 2);
+        let mut bvh_nodes: Vec<BVHNode> = Vec::with_capacity(reference_count * 2);
 
         bvh_triangles.iter_mut().for_each(|triangle|
             {
@@ -188,7 +403,7 @@ impl BVHConstructor
         };
 
         bvh_nodes.push(root);
-        for _ in 1..prim_count * 2
+        for _ in 1..reference_count * 2
         {
             bvh_nodes.push(
                 BVHNode {
@@ -208,113 +423,113 @@ impl BVHConstructor
             triangles: bvh_triangles,
             bvh_nodes,
             triangle_idx,
-            triangle_tmp: Vec::new(),
+            triangle_tmp: vec![0; reference_count],
             root_node_idx,
             nodes_used: 2,
             triangle_ptr: prim_count,
             spatial_splits,
-            is_spatial: false,
-            bin_size
+            prim_count,
+            options: *options
         };
 
-        bvh.subdivide(root_node_idx, 0);
-
-        /* println!("SAH:            {}", bvh.get_total_sah(0));
-         println!("nodes:          {}", bvh.get_node_count(0));
-         println!("leafs:          {}", bvh.get_leaf_count(0));
-         println!("spatial splits: {}", bvh.spatial_splits);
-         println!("SAH cost:       {}", bvh.sah_cost(0));*/
-
-        return bvh;
-    }
-
-    fn get_total_sah(&self, node_idx: usize) -> f32
-    {
-        let node = &self.bvh_nodes[node_idx];
-        if node.is_leaf()
-        {
-            return node.bounds.area();
-        }
-        return self.get_total_sah(node.left_first) + self.get_total_sah(node.left_first + 1);
-    }
+        bvh.subdivide(root_node_idx, slack);
 
-    fn get_node_count(&self, node_idx: usize) -> u32
-    {
-        let node = &self.bvh_nodes[node_idx];
-        if node.is_leaf()
+        if slack > 0 && prim_count > 0
         {
-            return 1;
+            let mut triangle_idx = Vec::with_capacity(reference_count);
+            bvh.finalize_sbvh(root_node_idx, &mut triangle_idx);
+            bvh.triangle_idx = triangle_idx;
         }
-        return self.get_node_count(node.left_first) + self.get_node_count(node.left_first + 1) + 1;
-    }
+        bvh.bvh_nodes.truncate(bvh.nodes_used);
 
-    fn get_leaf_count(&self, node_idx: usize) -> u32
-    {
-        let node = &self.bvh_nodes[node_idx];
-        if node.is_leaf()
-        {
-            return 1;
-        }
-        return self.get_leaf_count(node.left_first) + self.get_leaf_count(node.left_first + 1);
+        return bvh;
     }
 
-    fn sah_cost(&self, node_idx: usize) -> f32
+    pub fn stats(&self) -> BVHStats
     {
-        let node = &self.bvh_nodes[node_idx];
-        let area = node.bounds.area();
-        if node.is_leaf()
-        {
-            return area * (node.tri_count as f32);
-        }
-        let mut cost: f32 = 0.0;
-        cost += self.sah_cost(node.left_first);
-        cost += self.sah_cost(node.left_first + 1);
-        cost += area;
-        if node_idx == 0
-        {
-            cost *= 1.0 / area;
-        }
-        return cost;
+        return BVHStats::from_nodes(&self.bvh_nodes, self.prim_count, self.spatial_splits);
     }
 
-    fn finalize_sbvh(&mut self, node_idx: usize)
+    // map the duplicated triangles back to the triangles of the mesh and drop the slack that was left unused
+    fn finalize_sbvh(&mut self, node_idx: usize, triangle_idx: &mut Vec<usize>)
     {
         let node = self.bvh_nodes[node_idx];
         if node.is_leaf()
         {
+            self.bvh_nodes[node_idx].left_first = triangle_idx.len();
             for i in 0..node.tri_count
             {
                 let idx = self.triangle_idx[node.left_first + i];
-                self.triangle_idx[node.left_first + i] = self.triangles[idx].internal_triangle.tri_idx as usize;
+                triangle_idx.push(self.triangles[idx].internal_triangle.tri_idx as usize);
             }
             return;
         }
-        self.finalize_sbvh(node.left_first);
-        self.finalize_sbvh(node.left_first + 1);
+        self.finalize_sbvh(node.left_first, triangle_idx);
+        self.finalize_sbvh(node.left_first + 1, triangle_idx);
+    }
+
+    // fold over the triangle references of a node, large nodes are folded in parallel,
+    // the merges are exact (min, max, union and counts) so the result does not depend on the number of threads
+    fn fold_references<T, F, M>(triangle_idx: &[usize], identity: T, fold: F, merge: M) -> T
+    where
+        T: Clone + Send + Sync,
+        F: Fn(T, &usize) -> T + Send + Sync,
+        M: Fn(T, T) -> T + Send + Sync
+    {
+        if triangle_idx.len() < PARALLEL_BINNING_THRESHOLD
+        {
+            return triangle_idx.iter().fold(identity, fold);
+        }
+        return triangle_idx.par_iter().fold(|| identity.clone(), fold).reduce(|| identity.clone(), merge);
     }
 
-    fn find_best_object_split_plane(&mut self, node: &BVHNode, axis: &mut usize, split_pos: &mut f32, l_box: &mut AABB, r_box: &mut AABB) -> f32
+    fn find_best_object_split_plane(triangles: &[BVHTriangle], triangle_idx: &[usize], options: &BVHBuildOptions, axis: &mut usize, split_pos: &mut f32, l_box: &mut AABB, r_box: &mut AABB) -> f32
     {
         let mut best_cost: f32 = 1e30;
         for a in 0..3
         {
-            let mut bounds_min: f32 = 1e30;
-            let mut bounds_max: f32 = -1e30;
-            for i in 0..node.tri_count
-            {
-                let triangle = &self.triangles[self.triangle_idx[node.left_first + i]];
-
-                let triangle_center = triangle.bounds.center( a );
-                bounds_min = bounds_min.min( triangle_center );
-                bounds_max = bounds_max.max( triangle_center );
-            }
+            let (bounds_min, bounds_max) = BVHConstructor::fold_references(
+                triangle_idx,
+                (1e30, -1e30),
+                |(bounds_min, bounds_max): (f32, f32), idx| {
+                    let triangle_center = triangles[*idx].bounds.center( a );
+                    return (bounds_min.min( triangle_center ), bounds_max.max( triangle_center ));
+                },
+                |left, right| (left.0.min(right.0), left.1.max(right.1)));
             if bounds_min == bounds_max
             {
                 continue;
             }
+
+            // every triangle lands in the bin after the last split plane it is not left of,
+            // so the left side of a plane is the union of the bins before it
+            let bin_width = (bounds_max - bounds_min) / (options.bins as f32);
+            let bins = BVHConstructor::fold_references(
+                triangle_idx,
+                vec![(AABB::from_empty(), 0u32); options.bins],
+                |mut bins, idx| {
+                    let triangle = &triangles[*idx];
+                    let triangle_center = triangle.bounds.center( a );
+                    let mut b = 0;
+                    while b + 1 < options.bins && triangle_center >= bounds_min + bin_width * ((b + 1) as f32)
+                    {
+                        b += 1;
+                    }
+                    bins[b].0.grow_aabb( &triangle.bounds );
+                    bins[b].1 += 1;
+                    return bins;
+                },
+                |mut left, right| {
+                    for (bin, other) in left.iter_mut().zip(right.iter())
+                    {
+                        bin.0.grow_aabb( &other.0 );
+                        bin.1 += other.1;
+                    }
+                    return left;
+                });
+
             // loop over split plane candidates
-            let bin_width = (bounds_max - bounds_min) / (self.bin_size as f32);
-            for b in 1..self.bin_size
+            for b in 1..options.bins
             {
                 let plane = bounds_min + bin_width * (b as f32);
 
@@ -323,19 +538,15 @@ impl BVHConstructor
 
                 let mut left_count: u32 = 0;
                 let mut right_count: u32 = 0;
-                for i in 0..node.tri_count
+                for (bin_bounds, bin_count) in &bins[..b]
                 {
-                    let triangle = &self.triangles[self.triangle_idx[node.left_first + i]];
-                    if triangle.bounds.center( a ) < plane
-                    {
-                        left_box.grow_aabb( &triangle.bounds );
-                        left_count += 1;
-                    }
-                    else
-                    {
-                        right_box.grow_aabb( &triangle.bounds );
-                        right_count += 1;
-                    }
+                    left_box.grow_aabb( bin_bounds );
+                    left_count += bin_count;
+                }
+                for (bin_bounds, bin_count) in &bins[b..]
+                {
+                    right_box.grow_aabb( bin_bounds );
+                    right_count += bin_count;
                 }
                 let plane_cost = (left_count as f32) * left_box.area() + (right_count as f32) * right_box.area();
                 if plane_cost < best_cost
@@ -353,8 +564,11 @@ impl BVHConstructor
         return best_cost;
     }
 
-    fn find_best_spatial_split_plane(&mut self, node: &BVHNode, axis: &mut usize, split_pos: &mut f32, n_left: &mut usize, n_right: &mut usize, bounds_left: &mut AABB, bounds_right: &mut AABB, splitted: &mut i32) -> f32
+    fn find_best_spatial_split_plane(&self, node: &BVHNode, axis: &mut usize, split_pos: &mut f32, n_left: &mut usize, n_right: &mut usize, bounds_left: &mut AABB, bounds_right: &mut AABB, splitted: &mut i32) -> f32
     {
+        let triangles = &self.triangles;
+        let triangle_idx = &self.triangle_idx[node.left_first..node.left_first + node.tri_count];
+
         let mut best_cost = 1e30;
         for a in 0..3
         {
@@ -366,45 +580,42 @@ impl BVHConstructor
             }
 
             // calculate cost of spatial splits
-            let bin_extend = (bounds_max - bounds_min) / (self.bin_size as f32);
-            for b in 1..self.bin_size
+            let bin_extend = (bounds_max - bounds_min) / (self.options.bins as f32);
+            for b in 1..self.options.bins
             {
                 // calculate spatial split plane position
                 let pos = bounds_min + (b as f32) * bin_extend;
                 // construct left and right bounding box
-                let mut left_bounds = AABB::from_empty();
-                let mut right_bounds = AABB::from_empty();
                 let mut left_box = AABB::from_bounds(&node.bounds.min_bound, &node.bounds.max_bound);
                 let mut right_box = AABB::from_bounds(&node.bounds.min_bound, &node.bounds.max_bound);
 
                 left_box.max_bound.set_axis(a, pos - EPSILON);
                 right_box.min_bound.set_axis(a, pos + EPSILON);
 
-                let mut left_count: usize = 0;
-                let mut right_count: usize = 0;
-                // loop over triangles
-                for i in 0..node.tri_count
-                {
-                    // fetch a triangle
-                    let triangle = &self.triangles[self.triangle_idx[node.left_first + i]];
-
-                    // extend left and right side
-                    let left_triangle = Clipped::new(&triangle, &left_box);
+                // clip every triangle against both sides and extend them with the part that remains
+                let (left_bounds, left_count, right_bounds, right_count) = BVHConstructor::fold_references(
+                    triangle_idx,
+                    (AABB::from_empty(), 0usize, AABB::from_empty(), 0usize),
+                    |(mut left_bounds, mut left_count, mut right_bounds, mut right_count), idx| {
+                        let triangle = &triangles[*idx];
 
-                    if left_triangle.vertices > 2
-                    {
-                        left_bounds.grow_aabb( &left_triangle.bounds );
-                        left_count += 1;
-                    }
+                        let left_triangle = Clipped::new(&triangle, &left_box);
+                        if left_triangle.vertices > 2
+                        {
+                            left_bounds.grow_aabb( &left_triangle.bounds );
+                            left_count += 1;
+                        }
 
-                    let right_triangle = Clipped::new(&triangle, &right_box);
+                        let right_triangle = Clipped::new(&triangle, &right_box);
+                        if right_triangle.vertices > 2
+                        {
+                            right_bounds.grow_aabb( &right_triangle.bounds );
+                            right_count += 1;
+                        }
+                        return (left_bounds, left_count, right_bounds, right_count);
+                    },
+                    |left, right| (left.0.union(&right.0), left.1 + right.1, left.2.union(&right.2), left.3 + right.3));
 
-                    if right_triangle.vertices > 2
-                    {
-                        right_bounds.grow_aabb( &right_triangle.bounds );
-                        right_count += 1;
-                    }
-                }
                 // calculate cost for this split plane
                 if left_count > 0 && right_count > 0
                 {
@@ -430,15 +641,141 @@ impl BVHConstructor
         return best_cost;
     }
 
-    fn calculate_node_cost(node: &BVHNode) -> f32
+    fn calculate_node_cost(options: &BVHBuildOptions, node: &BVHNode) -> f32
     {
         let e = node.bounds.max_bound - node.bounds.min_bound;
         let area = e.x * e.y + e.y * e.z + e.z * e.x;
-        return (node.tri_count as f32) * area;
+        return options.intersection_cost * (node.tri_count as f32) * area;
+    }
+
+    // full sah cost of a split from the summed count times area of its children
+    fn calculate_split_cost(options: &BVHBuildOptions, node: &BVHNode, children_cost: f32) -> f32
+    {
+        return options.traversal_cost * node.bounds.area() + options.intersection_cost * children_cost;
+    }
+
+    // move the triangle references around the split plane, returns how many of them are left of it
+    fn partition(triangles: &[BVHTriangle], triangle_idx: &mut [usize], axis: usize, split_pos: f32) -> usize
+    {
+        let mut i: usize = 0;
+        let mut j: i32 = (triangle_idx.len() as i32) - 1;
+
+        while (i as i32) <= j
+        {
+            let jx = j as usize;
+            let triangle = &triangles[triangle_idx[i]];
+            if triangle.bounds.center(axis) < split_pos
+            {
+                i += 1;
+            }
+            else {
+                if i != jx
+                {
+                    triangle_idx.swap(i, jx);
+                }
+                j -= 1;
+            }
+        }
+        return i;
+    }
+
+    // move a node of a subtree that was built on its own, only interior nodes point at other nodes
+    fn relocate(node: &BVHNode, offset: usize) -> BVHNode
+    {
+        let mut node = *node;
+        if !node.is_leaf()
+        {
+            node.left_first += offset;
+        }
+        return node;
+    }
+
+    // build the subtree of a node without slack, only the triangle references of the node itself are moved
+    // so large children are built in parallel, the subtrees are merged in a fixed order which keeps the layout
+    // the same for any number of threads, the first returned node is the node itself
+    fn build_subtree(triangles: &[BVHTriangle], triangle_idx: &mut [usize], node: BVHNode, options: &BVHBuildOptions) -> Vec<BVHNode>
+    {
+        let mut nodes = vec![node];
+
+        let mut split_axis: usize = 0;
+        let mut split_pos: f32 = 0.0;
+        let mut left_box = AABB::from_empty();
+        let mut right_box = AABB::from_empty();
+
+        let no_split_cost = BVHConstructor::calculate_node_cost(options, &node);
+        let must_split = node.tri_count > options.max_leaf_size;
+        let split_cost = BVHConstructor::find_best_object_split_plane(triangles, triangle_idx, options, &mut split_axis, &mut split_pos, &mut left_box, &mut right_box);
+        if split_cost >= 1e30 || (BVHConstructor::calculate_split_cost(options, &node, split_cost) >= no_split_cost && !must_split)
+        {
+            return nodes;
+        }
+
+        let left_count = BVHConstructor::partition(triangles, triangle_idx, split_axis, split_pos);
+        if left_count == 0 || left_count == node.tri_count
+        {
+            return nodes;
+        }
+
+        let left = BVHNode{
+            bounds: left_box,
+            tri_count: left_count,
+            left_first: node.left_first
+        };
+        let right = BVHNode{
+            bounds: right_box,
+            tri_count: node.tri_count - left_count,
+            left_first: node.left_first + left_count
+        };
+
+        let (left_idx, right_idx) = triangle_idx.split_at_mut(left_count);
+        let (left_nodes, right_nodes) = if node.tri_count >= PARALLEL_SUBTREE_THRESHOLD
+        {
+            rayon::join(
+                || BVHConstructor::build_subtree(triangles, left_idx, left, options),
+                || BVHConstructor::build_subtree(triangles, right_idx, right, options))
+        }
+        else
+        {
+            (BVHConstructor::build_subtree(triangles, left_idx, left, options), BVHConstructor::build_subtree(triangles, right_idx, right, options))
+        };
+
+        // the children are stored next to each other, followed by the rest of the left and then of the right subtree
+        let left_offset = 2;
+        let right_offset = left_nodes.len() + 1;
+        nodes[0].left_first = 1;
+        nodes[0].tri_count = 0;
+        nodes.push(BVHConstructor::relocate(&left_nodes[0], left_offset));
+        nodes.push(BVHConstructor::relocate(&right_nodes[0], right_offset));
+        nodes.extend(left_nodes[1..].iter().map(|child| BVHConstructor::relocate(child, left_offset)));
+        nodes.extend(right_nodes[1..].iter().map(|child| BVHConstructor::relocate(child, right_offset)));
+        return nodes;
+    }
+
+    // nodes without slack can not split spatially anymore, their subtree is built on its own
+    // and placed behind the nodes that are already in use
+    fn subdivide_without_slack(&mut self, node_idx: usize)
+    {
+        let node = self.bvh_nodes[node_idx];
+        let triangle_idx = &mut self.triangle_idx[node.left_first..node.left_first + node.tri_count];
+        let nodes = BVHConstructor::build_subtree(&self.triangles, triangle_idx, node, &self.options);
+
+        let offset = self.nodes_used - 1;
+        self.bvh_nodes[node_idx] = BVHConstructor::relocate(&nodes[0], offset);
+        for (i, child) in nodes[1..].iter().enumerate()
+        {
+            self.bvh_nodes[self.nodes_used + i] = BVHConstructor::relocate(child, offset);
+        }
+        self.nodes_used += nodes.len() - 1;
     }
 
     fn subdivide(&mut self, node_idx: usize, slack: usize)
     {
+        if slack == 0
+        {
+            self.subdivide_without_slack(node_idx);
+            return;
+        }
+
         let node = self.bvh_nodes[node_idx].clone();
         let mut obj_split_axis: usize = 0;
         let mut spatial_split_axis: usize = 0;
@@ -448,16 +785,22 @@ impl BVHConstructor
 
         let mut obj_split_pos: f32 = 0.0;
         let mut spatial_split_pos: f32 = 0.0;
-        let no_split_cost: f32 = BVHConstructor::calculate_node_cost(&node);
+        let no_split_cost: f32 = BVHConstructor::calculate_node_cost(&self.options, &node);
+        let must_split = node.tri_count > self.options.max_leaf_size;
 
-        let obj_split_cost = self.find_best_object_split_plane(&node, &mut obj_split_axis, &mut obj_split_pos, &mut left_box, &mut right_box);
+        let triangle_idx = &self.triangle_idx[node.left_first..node.left_first + node.tri_count];
+        let obj_split_cost = BVHConstructor::find_best_object_split_plane(&self.triangles, triangle_idx, &self.options, &mut obj_split_axis, &mut obj_split_pos, &mut left_box, &mut right_box);
 
-        if self.is_spatial
+        if self.options.split_mode == BVHSplitMode::Spatial
         {
+            // disjoint children have no overlap, the area of their intersection would not be meaningful
+            let overlap = left_box.intersection( &right_box );
+            let e = overlap.max_bound - overlap.min_bound;
+            let overlap_area = if obj_split_cost < 1e30 && e.x >= 0.0 && e.y >= 0.0 && e.z >= 0.0 { overlap.area() } else { 0.0 };
             let root_area = self.bvh_nodes[0].bounds.area();
-            let lambda = left_box.intersection( &right_box ).area() / root_area;
+            let lambda = overlap_area / root_area;
 
-            if lambda > 1e-5
+            if lambda > self.options.spatial_alpha
             {
                 let mut n_left: usize = 0;
                 let mut n_right: usize = 0;
@@ -471,7 +814,7 @@ impl BVHConstructor
 
                 if spatial_split_cost < obj_split_cost && splitted < (slack as i32)
                 {
-                    if spatial_split_cost >= no_split_cost
+                    if BVHConstructor::calculate_split_cost(&self.options, &node, spatial_split_cost) >= no_split_cost && !must_split
                     {
                         return; // don't split, not worth it
                     }
@@ -539,6 +882,17 @@ impl BVHConstructor
                         }
                     }
 
+                    // all straddling triangles ended up on one side, keep the node as a leaf
+                    if left_count == 0 || right_count == 0
+                    {
+                        for i in 0..right_count
+                        {
+                            self.triangle_idx[node.left_first + i] = self.triangle_tmp[right_pos + i];
+                        }
+                        self.bvh_nodes[node_idx].tri_count = left_count + right_count;
+                        return;
+                    }
+
                     let slack = ((slack as i32) - splitted) as usize;
                     let half_slack = slack / 2;
 
@@ -573,30 +927,13 @@ impl BVHConstructor
             }
         }
 
-        if obj_split_cost >= no_split_cost
+        if obj_split_cost >= 1e30 || (BVHConstructor::calculate_split_cost(&self.options, &node, obj_split_cost) >= no_split_cost && !must_split)
         {
             return;
         }
 
-        let mut i = node.left_first;
-        let mut j: i32 = ((i + node.tri_count) as i32) - 1;
-
-        while (i as i32) <= j
-        {
-            let jx = j as usize;
-            let triangle = &self.triangles[self.triangle_idx[i]];
-            if triangle.bounds.center(obj_split_axis) < obj_split_pos
-            {
-                i += 1;
-            }
-            else {
-                if i != jx
-                {
-                    self.triangle_idx.swap(i, jx);
-                }
-                j -= 1;
-            }
-        }
+        let triangle_idx = &mut self.triangle_idx[node.left_first..node.left_first + node.tri_count];
+        let i = node.left_first + BVHConstructor::partition(&self.triangles, triangle_idx, obj_split_axis, obj_split_pos);
 
         let half_slack = slack / 2;
         let left_count = i - node.left_first;
@@ -629,4 +966,57 @@ impl BVHConstructor
         self.subdivide(left_child_idx, half_slack);
         self.subdivide(right_child_idx, half_slack);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests
+{
+    use super::*;
+    use crate::render_components::compute_bounds_from_triangles;
+
+    // scattered triangles of mixed sizes, shared by the tests of the bvh cache
+    pub(crate) fn test_triangles(count: usize) -> Vec<Triangle>
+    {
+        let mut seed: u32 = 12345;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            return (seed % 10000) as f32 / 10000.0;
+        };
+
+        let mut triangles = Vec::new();
+        for i in 0..count as u32
+        {
+            let origin = Float3::from_xyz(random() * 100.0, random() * 100.0, random() * 100.0);
+            let size = if i % 7 == 0 { 20.0 } else { 1.0 };
+            triangles.push(Triangle{
+                vertex0: origin,
+                vertex1: origin + Float3::from_xyz(random() * size, random(), random()),
+                vertex2: origin + Float3::from_xyz(random(), random() * size, random()),
+                tri_idx: i
+            });
+        }
+        return triangles;
+    }
+
+    fn build_with_threads(triangles: &Vec<Triangle>, options: &BVHBuildOptions, num_threads: usize) -> BVH
+    {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap();
+        return pool.install(|| BVH::from_mesh(triangles, &compute_bounds_from_triangles(triangles), options));
+    }
+
+    #[test]
+    fn parallel_build_matches_serial_build()
+    {
+        // enough triangles to bin and build subtrees in parallel
+        let triangles = test_triangles(PARALLEL_BINNING_THRESHOLD * 2);
+        for options in [BVHBuildOptions::new(), BVHBuildOptions::spatial()]
+        {
+            let serial = build_with_threads(&triangles, &options, 1);
+            let parallel = build_with_threads(&triangles, &options, 8);
+            assert_eq!(serial.bvh_nodes, parallel.bvh_nodes);
+            assert_eq!(serial.triangle_idx, parallel.triangle_idx);
+        }
+    }
+}
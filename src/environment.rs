@@ -0,0 +1,84 @@
+use std::f32::consts::PI;
+use log::info;
+use crate::math::*;
+use crate::material::luminance;
+
+// equirectangular environment map with the distributions to importance sample it
+// the rows run from the top (+y) to the bottom (-y) of the sphere
+pub struct EnvironmentMap
+{
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Float4>,
+
+    // cumulative distribution over the rows and, per row, over the pixels in the row
+    pub marginal_cdf: Vec<f32>,
+    pub conditional_cdf: Vec<f32>
+}
+
+impl EnvironmentMap
+{
+    pub fn from_file(path: &std::path::Path) -> Result<Self, image::ImageError>
+    {
+        info!("loading environment map {}", path.display());
+
+        let img = image::open(path)?.into_rgb32f();
+        let width = img.width();
+        let height = img.height();
+
+        let mut pixels: Vec<Float4> = Vec::with_capacity((width * height) as usize);
+        for pixel in img.pixels()
+        {
+            pixels.push(Float4::from_xyzw(pixel[0].max(0.0), pixel[1].max(0.0), pixel[2].max(0.0), 0.0));
+        }
+
+        let (marginal_cdf, conditional_cdf) = EnvironmentMap::build_distribution(&pixels, width as usize, height as usize);
+
+        return Ok(EnvironmentMap{
+            width,
+            height,
+            pixels,
+            marginal_cdf,
+            conditional_cdf
+        });
+    }
+
+    // pixels are weighted by their luminance and by the solid angle they cover,
+    // the rows near the poles are squeezed together by the projection
+    fn build_distribution(pixels: &Vec<Float4>, width: usize, height: usize) -> (Vec<f32>, Vec<f32>)
+    {
+        let mut marginal_cdf: Vec<f32> = vec![0.0; height];
+        let mut conditional_cdf: Vec<f32> = vec![0.0; width * height];
+
+        let mut total = 0.0;
+        for y in 0..height
+        {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+
+            let mut row_total = 0.0;
+            for x in 0..width
+            {
+                let pixel = &pixels[y * width + x];
+                row_total += luminance(&Float3::from_xyz(pixel.x, pixel.y, pixel.z)) * sin_theta;
+                conditional_cdf[y * width + x] = row_total;
+            }
+
+            // a black row is sampled uniformly, it is never picked by the marginal distribution anyway
+            for x in 0..width
+            {
+                let idx = y * width + x;
+                conditional_cdf[idx] = if row_total > 0.0 { conditional_cdf[idx] / row_total } else { (x + 1) as f32 / width as f32 };
+            }
+
+            total += row_total;
+            marginal_cdf[y] = total;
+        }
+
+        for y in 0..height
+        {
+            marginal_cdf[y] = if total > 0.0 { marginal_cdf[y] / total } else { (y + 1) as f32 / height as f32 };
+        }
+
+        return (marginal_cdf, conditional_cdf);
+    }
+}
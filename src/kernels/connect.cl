@@ -1,6 +1,149 @@
+#include "src/kernels/objects/scene.cl"
+#include "src/kernels/objects/lights.cl"
 
+// connect.cl
+// Traces the shadow rays of a bounce and adds the light of unoccluded rays to the light layer they belong to
+// the light is attenuated by the media the shadow ray passes through, surfaces that only bound a medium do not block it
 
-__kernel void connect()
+// the most medium boundaries a shadow ray passes through before it is treated as occluded
+#define MAX_MEDIUM_BOUNDARIES 8
+
+__kernel void connect(
+    uint bounce,
+    uint num_primary_rays,
+    __global uint* num_rays,
+    __global float* shadow_ray_ts,
+    __global float3* shadow_ray_origins,
+    __global float3* shadow_ray_directions,
+    __global uint* shadow_ray_write_back_ids,
+    __global float3* shadow_ray_write_back_lights,
+    __global float3* light,
+    __global uint* shadow_ray_media,
+    uint num_objects,
+    __global uint* obj_mesh_ids,
+    __global uint* obj_mat_ids,
+    __global struct mat4* obj_inv_transforms,
+    __global uint* bvh_offsets,
+    __global uint* mesh_offsets,
+    __global float3* bvh_min_bounds,
+    __global float3* bvh_max_bounds,
+    __global uint* bvh_tri_counts,
+    __global uint* bvh_left_firsts,
+    __global uint* bvh_triangle_offsets,
+    __global struct triangle* bvh_triangles,
+    __global uint* mat_offsets,
+    __global float* mat_refraction_index,
+    __global uint* mesh_triangle_mat_ids,
+    __global uint* mat_media,
+    __global struct medium* media,
+    uint fog_medium,
+    float3 fog_min_bound,
+    float3 fog_max_bound,
+    uint num_medium_boundaries,
+    __global float3* tlas_min_bounds,
+    __global float3* tlas_max_bounds,
+    __global uint* tlas_obj_counts,
+    __global uint* tlas_left_firsts,
+    __global uint* tlas_obj_ids
+)
 {
+    uint idx = get_global_id(0);
+
+    if (idx >= num_rays[bounce * 2 + 1])
+    {
+        return;
+    }
+
+    float shadow_ray_t = shadow_ray_ts[idx];
+    float3 shadow_ray_origin = shadow_ray_origins[idx];
+    float3 shadow_ray_direction = shadow_ray_directions[idx];
+    uint medium_idx = shadow_ray_media[idx];
+    float3 transmittance = (float3)1;
+
+    for (uint crossing = 0; ; crossing++)
+    {
+        float occlusion_t = shadow_ray_t;
+        if (!occlude_meshes(
+            &occlusion_t,
+            &shadow_ray_origin,
+            &shadow_ray_direction,
+            num_objects,
+            obj_mesh_ids,
+            obj_inv_transforms,
+            bvh_offsets,
+            bvh_triangle_offsets,
+            bvh_min_bounds,
+            bvh_max_bounds,
+            bvh_tri_counts,
+            bvh_left_firsts,
+            bvh_triangles,
+            tlas_min_bounds,
+            tlas_max_bounds,
+            tlas_obj_counts,
+            tlas_left_firsts,
+            tlas_obj_ids))
+        {
+            transmittance *= medium_transmittance(medium_idx, &shadow_ray_origin, &shadow_ray_direction, shadow_ray_t, media, fog_medium, fog_min_bound, fog_max_bound);
+            break;
+        }
+
+        if (num_medium_boundaries == 0 || crossing >= MAX_MEDIUM_BOUNDARIES)
+        {
+            return;
+        }
+
+        // find the closest surface, only the surfaces that bound a medium let the light through
+        float hit_t = shadow_ray_t;
+        float3 hit_geometric_normal;
+        uint hit_tri_idx;
+        uint hit_prim_idx;
+        uint hit_mesh_idx;
+        uint hit_obj_idx;
+        uint hit_mat_idx = MAX_UINT;
+        intersect_scene_triangle(
+            &hit_t,
+            &shadow_ray_origin,
+            &shadow_ray_direction,
+            &hit_tri_idx,
+            &hit_prim_idx,
+            &hit_mesh_idx,
+            &hit_obj_idx,
+            &hit_mat_idx,
+            &hit_geometric_normal,
+            num_objects,
+            obj_mesh_ids,
+            obj_mat_ids,
+            obj_inv_transforms,
+            bvh_offsets,
+            mesh_offsets,
+            bvh_min_bounds,
+            bvh_max_bounds,
+            bvh_tri_counts,
+            bvh_left_firsts,
+            bvh_triangle_offsets,
+            bvh_triangles,
+            mesh_triangle_mat_ids,
+            tlas_min_bounds,
+            tlas_max_bounds,
+            tlas_obj_counts,
+            tlas_left_firsts,
+            tlas_obj_ids);
+
+        if (hit_obj_idx == MAX_UINT ||
+            mat_media[hit_mat_idx] == MAX_UINT ||
+            material_refraction_index(hit_mat_idx, mat_offsets, mat_refraction_index) > 0.0f)
+        {
+            return;
+        }
+
+        // continue behind the boundary in the medium on the other side
+        transmittance *= medium_transmittance(medium_idx, &shadow_ray_origin, &shadow_ray_direction, hit_t, media, fog_medium, fog_min_bound, fog_max_bound);
+        medium_idx = dot(hit_geometric_normal, shadow_ray_direction) < 0.0f ? mat_media[hit_mat_idx] : MAX_UINT;
+        shadow_ray_origin += shadow_ray_direction * (hit_t + EPSILON);
+        shadow_ray_t -= hit_t + EPSILON;
+    }
 
-}
\ No newline at end of file
+    // light that is found from a bounce arrives through one extra bounce
+    uint light_idx = (bounce + 1) * num_primary_rays + shadow_ray_write_back_ids[idx];
+    light[light_idx] += shadow_ray_write_back_lights[idx] * transmittance;
+}
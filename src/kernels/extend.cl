@@ -1,6 +1,119 @@
+#include "src/kernels/objects/scene.cl"
+#include "src/kernels/objects/lights.cl"
 
+// extend.cl
+// Traces the compacted ray queue of a bounce against the scene
+// the queue is double buffered, bounce n reads from slot (n & 1) of the ray buffers
 
-__kernel void extend()
+__kernel void extend(
+    uint bounce,
+    uint num_primary_rays,
+    __global uint* num_rays,
+    __global float* ray_ts,
+    __global float3* ray_origins,
+    __global float3* ray_directions,
+    __global float3* ray_normals,
+    __global float3* ray_intersection_colors,
+    __global uint* ray_obj_ids,
+    __global float3* ray_geometric_normals,
+    __global uint* ray_mat_ids,
+    uint num_objects,
+    __global uint* obj_mesh_ids,
+    __global uint* obj_mat_ids,
+    __global struct mat4* obj_transforms,
+    __global struct mat4* obj_inv_transforms,
+    __global uint* bvh_offsets,
+    __global uint* mesh_offsets,
+    __global float3* bvh_min_bounds,
+    __global float3* bvh_max_bounds,
+    __global uint* bvh_tri_counts,
+    __global uint* bvh_left_firsts,
+    __global uint* bvh_triangle_offsets,
+    __global struct triangle* bvh_triangles,
+    __global uint3* mesh_vertex_ids,
+    __global float3* mesh_vertex_normals,
+    __global uint* mat_offsets,
+    __global uint* mat_colors,
+    __global float2* mesh_vertex_uvs,
+    __global uint* mat_albedo_textures,
+    __global uint* tex_offsets,
+    __global uint2* tex_dimensions,
+    __global uint* tex_wrap_modes,
+    __global uint* tex_pixels,
+    __global float4* mesh_vertex_tangents,
+    __global uint* mat_normal_textures,
+    __global uint* mesh_triangle_mat_ids,
+    __global float3* tlas_min_bounds,
+    __global float3* tlas_max_bounds,
+    __global uint* tlas_obj_counts,
+    __global uint* tlas_left_firsts,
+    __global uint* tlas_obj_ids
+)
 {
+    uint idx = get_global_id(0);
 
-}
\ No newline at end of file
+    // the queue only holds the rays that survived the previous bounce
+    if (idx >= num_rays[bounce * 2])
+    {
+        return;
+    }
+
+    uint ray_idx = (bounce & 1) * num_primary_rays + idx;
+
+    float ray_t = 1e30;
+    float3 ray_origin = ray_origins[ray_idx];
+    float3 ray_direction = ray_directions[ray_idx];
+    float3 ray_normal = ray_direction;
+    float3 ray_geometric_normal = ray_direction;
+    float3 ray_intersection_color = (float3)0;
+    uint ray_obj_idx = MAX_UINT;
+    uint ray_mat_idx = MAX_UINT;
+
+    intersect_scene(
+        &ray_t,
+        &ray_origin,
+        &ray_direction,
+        &ray_normal,
+        &ray_geometric_normal,
+        &ray_intersection_color,
+        &ray_obj_idx,
+        &ray_mat_idx,
+        num_objects,
+        obj_mesh_ids,
+        obj_mat_ids,
+        obj_transforms,
+        obj_inv_transforms,
+        bvh_offsets,
+        mesh_offsets,
+        bvh_min_bounds,
+        bvh_max_bounds,
+        bvh_tri_counts,
+        bvh_left_firsts,
+        bvh_triangle_offsets,
+        bvh_triangles,
+        mesh_vertex_ids,
+        mesh_vertex_normals,
+        mat_offsets,
+        mat_colors,
+        mesh_vertex_uvs,
+        mat_albedo_textures,
+        tex_offsets,
+        tex_dimensions,
+        tex_wrap_modes,
+        tex_pixels,
+        mesh_vertex_tangents,
+        mat_normal_textures,
+        mesh_triangle_mat_ids,
+        tlas_min_bounds,
+        tlas_max_bounds,
+        tlas_obj_counts,
+        tlas_left_firsts,
+        tlas_obj_ids);
+
+    ray_ts[ray_idx] = ray_t;
+    ray_normals[ray_idx] = ray_normal;
+    ray_geometric_normals[ray_idx] = ray_geometric_normal;
+    ray_intersection_colors[ray_idx] = ray_intersection_color;
+    ray_obj_ids[ray_idx] = ray_obj_idx;
+    ray_mat_ids[ray_idx] = ray_mat_idx;
+}
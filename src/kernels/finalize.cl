@@ -1,3 +1,17 @@
+#include "src/kernels/tools/constants.cl"
+#include "src/kernels/tools/color.cl"
+
+// clamp color under one and multiply to get byte values
+uint pack_color(float3 color)
+{
+    float3 one = (float3)1;
+    float3 ranged_color = min(color, one) * 255.0f;
+
+    uint r = (uint)ranged_color.x;
+    uint g = (uint)ranged_color.y;
+    uint b = (uint)ranged_color.z;
+    return (r << 16) + (g << 8) + b;
+}
 
 __kernel void albedo(
     __global float3* albedo,
@@ -6,19 +20,124 @@ __kernel void albedo(
 {
     uint idx = get_global_id(0);
 
-    float3 rendered_color = albedo[idx];
+    output_buffer[idx] = pack_color(albedo[idx]);
+}
 
-    // clamp color under one and multiply to get byte values
-    float3 one = (float3)1;
-    float3 ranged_color = min(rendered_color, one) * 255.0f;
+// visualizes the normals of the primary hits
+__kernel void normals(
+    __global float3* ray_normals,
+    __global uint* output_buffer
+)
+{
+    uint idx = get_global_id(0);
 
-    uint r = (uint)ranged_color.x;
-    uint g = (uint)ranged_color.y;
-    uint b = (uint)ranged_color.z;
-    output_buffer[idx] = (r << 16) + (g << 8) + b;
+    output_buffer[idx] = pack_color(ray_normals[idx] * 0.5f + (float3)0.5f);
+}
+
+// sums the filter weighted light of all samples of a pixel in a light layer
+float3 pixel_light(
+    __global float3* light,
+    __global float* ray_filter_weights,
+    uint layer,
+    uint num_primary_rays,
+    uint num_pixels,
+    uint pixel_idx
+)
+{
+    uint samples_per_pixel = num_primary_rays / num_pixels;
+    float3 sum = (float3)0;
+    for (uint s = 0; s < samples_per_pixel; s++)
+    {
+        uint ray_idx = s * num_pixels + pixel_idx;
+        sum += light[layer * num_primary_rays + ray_idx] * ray_filter_weights[ray_idx];
+    }
+    return sum;
 }
 
-__kernel void finalize()
+// sums the filter weights of all samples of a pixel
+float pixel_weight(
+    __global float* ray_filter_weights,
+    uint num_primary_rays,
+    uint num_pixels,
+    uint pixel_idx
+)
+{
+    uint samples_per_pixel = num_primary_rays / num_pixels;
+    float sum = 0.0f;
+    for (uint s = 0; s < samples_per_pixel; s++)
+    {
+        sum += ray_filter_weights[s * num_pixels + pixel_idx];
+    }
+    return sum;
+}
+
+// writes the sum of the light layers of this frame to the output, without accumulation
+__kernel void path_tracing(
+    uint tone_mapping,
+    float exposure,
+    uint num_bounces,
+    uint num_primary_rays,
+    __global float3* light,
+    __global float* ray_filter_weights,
+    __global uint* output_buffer
+)
 {
+    uint idx = get_global_id(0);
+    uint num_pixels = get_global_size(0);
+
+    float3 rendered_color = (float3)0;
+    for (uint i = 0; i <= num_bounces; i++)
+    {
+        rendered_color += pixel_light(light, ray_filter_weights, i, num_primary_rays, num_pixels, idx);
+    }
+
+    float filter_weight = pixel_weight(ray_filter_weights, num_primary_rays, num_pixels, idx);
+    rendered_color = filter_weight > 0.0f ? rendered_color / filter_weight : (float3)0;
+
+    output_buffer[idx] = pack_color(display_transform(rendered_color, tone_mapping, exposure));
+}
 
-}
\ No newline at end of file
+// blends the light layers of this frame into the accumulator and writes the sum of all layers to the output
+// when a light layer is given only that layer is written to the output
+__kernel void finalize(
+    uint rendered_frames,
+    uint light_layer,
+    uint tone_mapping,
+    float exposure,
+    uint num_bounces,
+    uint num_primary_rays,
+    __global float3* light,
+    __global float* ray_filter_weights,
+    __global float3* light_accumulator,
+    __global float* weight_accumulator,
+    __global uint* output_buffer
+)
+{
+    uint idx = get_global_id(0);
+    uint num_pixels = get_global_size(0);
+
+    // running average over all frames since the last reset
+    float weight = 1.0f / (float)rendered_frames;
+
+    // the filter weights are accumulated separately, the reconstructed pixel is the weighted light over the summed weights
+    float accumulated_weight = weight_accumulator[idx] * (1.0f - weight)
+        + pixel_weight(ray_filter_weights, num_primary_rays, num_pixels, idx) * weight;
+    weight_accumulator[idx] = accumulated_weight;
+
+    float3 rendered_color = (float3)0;
+    for (uint i = 0; i <= num_bounces; i++)
+    {
+        uint layer_idx = i * num_pixels + idx;
+        float3 frame_light = pixel_light(light, ray_filter_weights, i, num_primary_rays, num_pixels, idx);
+        float3 accumulated = light_accumulator[layer_idx] * (1.0f - weight) + frame_light * weight;
+        light_accumulator[layer_idx] = accumulated;
+        if (light_layer == MAX_UINT || light_layer == i)
+        {
+            rendered_color += accumulated;
+        }
+    }
+
+    rendered_color = accumulated_weight > 0.0f ? rendered_color / accumulated_weight : (float3)0;
+
+    output_buffer[idx] = pack_color(display_transform(rendered_color, tone_mapping, exposure));
+}
@@ -1,4 +1,7 @@
 #include "src/kernels/objects/scene.cl"
+#include "src/kernels/objects/lights.cl"
+#include "src/kernels/tools/random.cl"
+#include "src/kernels/tools/filter.cl"
 
 __kernel void generate_rays(
     uint glob_seed,
@@ -9,16 +12,25 @@ __kernel void generate_rays(
     float3 cam_top_left,
     float3 cam_bottom_left,
     float3 cam_top_right,
+    uint frame_idx,
+    uint pixel_filter,
+    __constant uchar* blue_noise_texture,
     __global uint* ray_write_back_ids,
     __global float* ray_ts,
     __global float3* ray_origins,
     __global float3* ray_directions,
     __global float3* ray_normals,
+    __global uint* ray_obj_ids,
     __global float3* ray_intersection_colors,
     __global float3* ray_energies,
     __global float3* ray_write_back_lights,
     __global float3* albedo,
     __global float3* light,
+    __global float* ray_filter_weights,
+    __global uint* ray_flags,
+    __global float3* ray_geometric_normals,
+    __global uint* ray_mat_ids,
+    __global uint* ray_media,
     uint num_objects,
     __global uint* obj_mesh_ids,
     __global uint* obj_mat_ids,
@@ -36,34 +48,66 @@ __kernel void generate_rays(
     __global float3* mesh_vertex_normals,
     __global uint* mat_offsets,
     __global uint* mat_colors,
-    __global uchar* mat_reflectiveness,
-    __global float* mat_refraction_index
+    __global float2* mesh_vertex_uvs,
+    __global uint* mat_albedo_textures,
+    __global uint* tex_offsets,
+    __global uint2* tex_dimensions,
+    __global uint* tex_wrap_modes,
+    __global uint* tex_pixels,
+    __global float4* mesh_vertex_tangents,
+    __global uint* mat_normal_textures,
+    __global uint* mesh_triangle_mat_ids,
+    __global float3* tlas_min_bounds,
+    __global float3* tlas_max_bounds,
+    __global uint* tlas_obj_counts,
+    __global uint* tlas_left_firsts,
+    __global uint* tlas_obj_ids
 )
 {
+    // every sample of a pixel gets its own rows of rays
     uint x = get_global_id(0);
-    uint y = get_global_id(1);
+    uint ray_y = get_global_id(1);
+    uint y = ray_y % screen_height;
+    uint pixel_sample = ray_y / screen_height;
+    uint samples_per_pixel = get_global_size(1) / screen_height;
+
+    // jitter the film position with blue noise, shifted by the same random offset for every pixel in a frame
+    uint seed = init_seed(glob_seed + pixel_sample);
+    float2 shift = (float2)(random_float(&seed), random_float(&seed));
+    float2 jitter = random_blue_noise_point(blue_noise_texture, frame_idx * samples_per_pixel + pixel_sample, x, y) + shift;
+    jitter -= floor(jitter);
+
+    // spread the samples over the filter footprint, the weight is applied when the samples are reconstructed
+    float2 offset = (jitter * 2.0f - (float2)1.0f) * filter_radius(pixel_filter);
 
     // get uv coordinate
-    float u = ((float)x) / (float)screen_width;
-    float v = ((float)y) / (float)screen_height;
+    float u = ((float)x + 0.5f + offset.x) / (float)screen_width;
+    float v = ((float)y + 0.5f + offset.y) / (float)screen_height;
 
     // get direction
     float3 p = cam_top_left + (cam_top_right - cam_top_left) * u + (cam_bottom_left - cam_top_left) * v;
     float3 ray_direction = normalize(p - cam_position);
-    uint idx = x + y * screen_width;
-    uint max_idx = screen_width * screen_height;
+    uint idx = x + ray_y * screen_width;
+    uint pixel_idx = x + y * screen_width;
+    uint max_idx = get_global_size(0) * get_global_size(1);
 
     float ray_t = 1e30;
     float3 ray_origin = cam_position;
     float3 ray_normal = ray_direction;
+    float3 ray_geometric_normal = ray_direction;
     float3 ray_intersection_color = ray_direction;
+    uint ray_obj_idx = MAX_UINT;
+    uint ray_mat_idx = MAX_UINT;
 
     intersect_scene(
         &ray_t,
         &ray_origin,
         &ray_direction,
         &ray_normal,
+        &ray_geometric_normal,
         &ray_intersection_color,
+        &ray_obj_idx,
+        &ray_mat_idx,
         num_objects,
         obj_mesh_ids,
         obj_mat_ids,
@@ -81,18 +125,42 @@ __kernel void generate_rays(
         mesh_vertex_normals,
         mat_offsets,
         mat_colors,
-        mat_reflectiveness,
-        mat_refraction_index);
+        mesh_vertex_uvs,
+        mat_albedo_textures,
+        tex_offsets,
+        tex_dimensions,
+        tex_wrap_modes,
+        tex_pixels,
+        mesh_vertex_tangents,
+        mat_normal_textures,
+        mesh_triangle_mat_ids,
+        tlas_min_bounds,
+        tlas_max_bounds,
+        tlas_obj_counts,
+        tlas_left_firsts,
+        tlas_obj_ids);
 
-    albedo[idx] = ray_intersection_color;
+    if (idx == pixel_idx)
+    {
+        albedo[pixel_idx] = ray_intersection_color;
+    }
     ray_write_back_ids[idx] = idx;
     ray_ts[idx] = ray_t;
     ray_origins[idx] = ray_origin;
     ray_directions[idx] = ray_direction;
     ray_normals[idx] = ray_normal;
+    ray_geometric_normals[idx] = ray_geometric_normal;
+    ray_obj_ids[idx] = ray_obj_idx;
+    ray_mat_ids[idx] = ray_mat_idx;
+    ray_intersection_colors[idx] = ray_intersection_color;
     ray_energies[idx] = (float3)1;
+    ray_filter_weights[idx] = filter_weight(pixel_filter, offset);
+    ray_flags[idx] = 0;
+
+    // the camera is never inside of the medium of a mesh, the fog is looked up from the bounds
+    ray_media[idx] = MAX_UINT;
 
-    for (uint i = 0; i < num_bounces; i++)
+    for (uint i = 0; i <= num_bounces; i++)
     {
         light[max_idx * i + idx] = (float3)0;
     }
@@ -1,3 +1,4 @@
+#pragma once
 #include "src/kernels/objects/aabb.cl"
 #include "src/kernels/objects/triangle.cl"
 #include "src/kernels/types/mat4.cl"
@@ -10,6 +11,7 @@ bool intersect_bvh(
     float3* ray_origin,
     float3* ray_direction,
     uint* ray_tri_idx,
+    uint* ray_prim_idx,
     float3* mesh_min_bounds,
     float3* mesh_max_bounds,
     uint* mesh_tri_counts,
@@ -45,6 +47,7 @@ bool intersect_bvh(
                 if (intersect_triangle(ray_t, ray_origin, ray_direction, &tr.vertex0, &tr.vertex1, &tr.vertex2))
                 {
                     *ray_tri_idx = tr.idx;
+                    *ray_prim_idx = left_first + i;
                     intersected = true;
                 }
             }
@@ -188,34 +191,3 @@ bool occlude_bvh(
 
     return false;
 }
-
-bool occlude_meshes(
-    float* ray_t,
-    float3* ray_origin,
-    float3* ray_direction,
-    uint num_meshes,
-    uint* mesh_offsets,
-    uint* mesh_triangle_offsets,
-    struct mat4* mesh_inv_transforms,
-    float3* mesh_min_bounds,
-    float3* mesh_max_bounds,
-    uint* mesh_tri_counts,
-    uint* mesh_left_firsts,
-    struct triangle* mesh_triangles
-    )
-{
-    for (int i = 0; i < num_meshes; i++)
-    {
-        struct mat4 mesh_inv_transform = mesh_inv_transforms[i];
-        float3 new_origin = transform_position(ray_origin, &mesh_inv_transform);
-        float3 new_direction = transform_vector(ray_direction, &mesh_inv_transform);
-        uint mesh_offset = mesh_offsets[i];
-        uint triangle_offset = mesh_triangle_offsets[i];
-
-        if (occlude_bvh(ray_t, &new_origin, &new_direction, mesh_min_bounds + mesh_offset, mesh_max_bounds + mesh_offset, mesh_tri_counts + mesh_offset, mesh_left_firsts + mesh_offset, mesh_triangles + triangle_offset))
-        {
-            return true;
-        }
-    }
-    return false;
-}
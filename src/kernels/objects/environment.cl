@@ -0,0 +1,120 @@
+#pragma once
+#include "src/kernels/tools/constants.cl"
+#include "src/kernels/tools/random.cl"
+#include "src/kernels/objects/sky.cl"
+
+// environment.cl
+// Contains all code related to looking up and importance sampling the equirectangular environment map
+// without an environment map the sky gradient is used
+
+// rotate a direction around the up axis
+float3 rotate_y(float3 direction, float angle)
+{
+    float c = cos(angle);
+    float s = sin(angle);
+    return (float3)(direction.x * c + direction.z * s, direction.y, direction.z * c - direction.x * s);
+}
+
+// index of the pixel that a direction in the space of the map falls in
+uint environment_pixel_idx(float3 direction, uint env_map_width, uint env_map_height)
+{
+    float u = atan2(direction.z, direction.x) * (0.5f * INV_PI) + 0.5f;
+    float v = acos(clamp(direction.y, -1.0f, 1.0f)) * INV_PI;
+    uint x = min((uint)(u * (float)env_map_width), env_map_width - 1);
+    uint y = min((uint)(v * (float)env_map_height), env_map_height - 1);
+    return y * env_map_width + x;
+}
+
+// radiance that arrives from a direction for rays that leave the scene
+float3 environment_radiance(
+    float3* direction,
+    float env_rotation,
+    float env_intensity,
+    uint env_map_width,
+    uint env_map_height,
+    float4* env_map_pixels
+)
+{
+    if (env_map_width == 0)
+    {
+        return sky_color(direction) * env_intensity;
+    }
+
+    float3 local_direction = rotate_y(*direction, -env_rotation);
+    return env_map_pixels[environment_pixel_idx(local_direction, env_map_width, env_map_height)].xyz * env_intensity;
+}
+
+// pdf over solid angle of a pixel in the map, the pixels near the poles cover less solid angle
+float environment_pixel_pdf(
+    uint x,
+    uint y,
+    float sin_theta,
+    uint env_map_width,
+    uint env_map_height,
+    float* env_map_marginal_cdf,
+    float* env_map_conditional_cdf
+)
+{
+    if (sin_theta <= 0.0f)
+    {
+        return 0.0f;
+    }
+
+    float pixel_probability = cdf_probability(env_map_marginal_cdf, y) * cdf_probability(env_map_conditional_cdf + y * env_map_width, x);
+    return pixel_probability * (float)(env_map_width * env_map_height) / (2.0f * PI * PI * sin_theta);
+}
+
+// pdf over solid angle of sampling a direction with sample_environment
+float environment_pdf(
+    float3* direction,
+    float env_rotation,
+    uint env_map_width,
+    uint env_map_height,
+    float* env_map_marginal_cdf,
+    float* env_map_conditional_cdf
+)
+{
+    float3 local_direction = rotate_y(*direction, -env_rotation);
+    uint pixel_idx = environment_pixel_idx(local_direction, env_map_width, env_map_height);
+    float sin_theta = sqrt(max(0.0f, 1.0f - local_direction.y * local_direction.y));
+    return environment_pixel_pdf(
+        pixel_idx % env_map_width,
+        pixel_idx / env_map_width,
+        sin_theta,
+        env_map_width,
+        env_map_height,
+        env_map_marginal_cdf,
+        env_map_conditional_cdf);
+}
+
+// sample a direction proportional to the brightness of the map, first a row and then a pixel in that row
+// returns the radiance arriving along the direction, the pdf is over solid angle
+float3 sample_environment(
+    uint* seed,
+    float env_rotation,
+    float env_intensity,
+    uint env_map_width,
+    uint env_map_height,
+    float4* env_map_pixels,
+    float* env_map_marginal_cdf,
+    float* env_map_conditional_cdf,
+    float3* direction,
+    float* pdf
+)
+{
+    uint y = sample_cdf(random_float(seed), env_map_marginal_cdf, env_map_height);
+    uint x = sample_cdf(random_float(seed), env_map_conditional_cdf + y * env_map_width, env_map_width);
+
+    // a uniform point inside the pixel
+    float u = ((float)x + random_float(seed)) / (float)env_map_width;
+    float v = ((float)y + random_float(seed)) / (float)env_map_height;
+    float phi = (u - 0.5f) * 2.0f * PI;
+    float theta = v * PI;
+    float sin_theta = sin(theta);
+
+    float3 local_direction = (float3)(sin_theta * cos(phi), cos(theta), sin_theta * sin(phi));
+    *direction = rotate_y(local_direction, env_rotation);
+    *pdf = environment_pixel_pdf(x, y, sin_theta, env_map_width, env_map_height, env_map_marginal_cdf, env_map_conditional_cdf);
+
+    return env_map_pixels[y * env_map_width + x].xyz * env_intensity;
+}
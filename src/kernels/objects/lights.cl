@@ -0,0 +1,321 @@
+#pragma once
+#include "src/kernels/tools/constants.cl"
+#include "src/kernels/tools/random.cl"
+#include "src/kernels/tools/color.cl"
+#include "src/kernels/objects/environment.cl"
+
+// lights.cl
+// Contains all code related to sampling light sources for next event estimation
+
+// keep in sync with the light structs in light.rs
+struct point_light
+{
+    float3 position;
+    float3 intensity;
+    float range;
+};
+
+struct spot_light
+{
+    float3 position;
+    float3 direction;
+    float3 intensity;
+    float range;
+    float cos_inner_angle;
+    float cos_outer_angle;
+};
+
+struct directional_light
+{
+    float3 direction;
+    float3 irradiance;
+    float cos_half_angle;
+};
+
+// sample a point uniformly by area on an emissive triangle
+// returns the radiance arriving along the direction, the pdf is over solid angle
+float3 sample_area_light(
+    float3* hit_point,
+    uint* seed,
+    uint num_lights,
+    float3* light_vertices,
+    float3* light_emissions,
+    float* light_areas,
+    float* light_cdf,
+    float3* light_direction,
+    float* light_distance,
+    float* light_pdf
+)
+{
+    // pick a light proportional to its power
+    uint light_idx = sample_cdf(random_float(seed), light_cdf, num_lights);
+    float light_probability = cdf_probability(light_cdf, light_idx);
+
+    float3 vertex0 = light_vertices[light_idx * 3];
+    float3 vertex1 = light_vertices[light_idx * 3 + 1];
+    float3 vertex2 = light_vertices[light_idx * 3 + 2];
+
+    // uniform barycentrics, the square root keeps the density constant over the area
+    float r0 = sqrt(random_float(seed));
+    float r1 = random_float(seed);
+    float3 light_point = vertex0 * (1.0f - r0) + vertex1 * (r0 * (1.0f - r1)) + vertex2 * (r0 * r1);
+
+    float3 to_light = light_point - *hit_point;
+    float distance_squared = dot(to_light, to_light);
+    *light_distance = sqrt(distance_squared);
+    *light_direction = to_light / *light_distance;
+
+    // the triangles emit on both sides
+    float3 light_normal = normalize(cross(vertex1 - vertex0, vertex2 - vertex0));
+    float cos_light = fabs(dot(light_normal, *light_direction));
+    if (cos_light <= 0.0f)
+    {
+        *light_pdf = 0.0f;
+        return (float3)0;
+    }
+
+    // convert the area pdf to solid angle
+    *light_pdf = light_probability * distance_squared / (light_areas[light_idx] * cos_light);
+    return light_emissions[light_idx];
+}
+
+// inverse square falloff, smoothly faded to zero at the range of the light
+float light_falloff(float distance_squared, float range)
+{
+    float falloff = 1.0f / max(distance_squared, EPSILON);
+    if (range > 0.0f)
+    {
+        float ratio = distance_squared / (range * range);
+        float window = clamp(1.0f - ratio * ratio, 0.0f, 1.0f);
+        falloff *= window * window;
+    }
+    return falloff;
+}
+
+// point and spot lights are delta lights, the direction towards them is fixed so the pdf is one
+float3 sample_point_light(
+    float3* hit_point,
+    struct point_light* light,
+    float3* light_direction,
+    float* light_distance,
+    float* light_pdf
+)
+{
+    float3 to_light = light->position - *hit_point;
+    float distance_squared = dot(to_light, to_light);
+    *light_distance = sqrt(distance_squared);
+    *light_direction = to_light / *light_distance;
+    *light_pdf = 1.0f;
+    return light->intensity * light_falloff(distance_squared, light->range);
+}
+
+float3 sample_spot_light(
+    float3* hit_point,
+    struct spot_light* light,
+    float3* light_direction,
+    float* light_distance,
+    float* light_pdf
+)
+{
+    float3 to_light = light->position - *hit_point;
+    float distance_squared = dot(to_light, to_light);
+    *light_distance = sqrt(distance_squared);
+    *light_direction = to_light / *light_distance;
+    *light_pdf = 1.0f;
+
+    // fade out between the inner and the outer cone
+    float cos_angle = dot(-*light_direction, light->direction);
+    float cone = smoothstep(light->cos_outer_angle, light->cos_inner_angle, cos_angle);
+    return light->intensity * (light_falloff(distance_squared, light->range) * cone);
+}
+
+// sample a direction inside the disk of a distant light, the irradiance is spread evenly over its solid angle
+float3 sample_directional_light(
+    uint* seed,
+    struct directional_light* light,
+    float3* light_direction,
+    float* light_distance,
+    float* light_pdf
+)
+{
+    float3 axis = -light->direction;
+    float solid_angle = 2.0f * PI * (1.0f - light->cos_half_angle);
+    *light_distance = 1e30;
+
+    // a disk without size is a delta light
+    if (solid_angle <= EPSILON)
+    {
+        *light_direction = axis;
+        *light_pdf = 1.0f;
+        return light->irradiance;
+    }
+
+    *light_direction = random_cone_direction(&axis, light->cos_half_angle, seed);
+    *light_pdf = 1.0f / solid_angle;
+    return light->irradiance / solid_angle;
+}
+
+// the environment, the emissive triangles and the analytic lights are sampled as separate strategies
+uint light_strategy_count(uint num_lights, uint num_analytic_lights)
+{
+    return 1 + (num_lights > 0 ? 1 : 0) + (num_analytic_lights > 0 ? 1 : 0);
+}
+
+// pdf over solid angle of sample_light for a direction that leaves the scene
+float environment_light_pdf(
+    float3* normal,
+    float3* direction,
+    uint num_lights,
+    uint num_analytic_lights,
+    float env_rotation,
+    uint env_map_width,
+    uint env_map_height,
+    float* env_map_marginal_cdf,
+    float* env_map_conditional_cdf
+)
+{
+    float strategy_probability = 1.0f / (float)light_strategy_count(num_lights, num_analytic_lights);
+    if (env_map_width > 0)
+    {
+        return environment_pdf(direction, env_rotation, env_map_width, env_map_height, env_map_marginal_cdf, env_map_conditional_cdf) * strategy_probability;
+    }
+    if (dot(*normal, *normal) <= 0.0f)
+    {
+        return 0.25f * INV_PI * strategy_probability;
+    }
+    return max(dot(*normal, *direction), 0.0f) * INV_PI * strategy_probability;
+}
+
+// pdf over solid angle of sample_light for a direction that hits an emissive triangle,
+// the triangles are picked proportional to their power so their area cancels out
+float area_light_pdf(
+    float3 emission,
+    float light_distance,
+    float cos_light,
+    float light_total_power,
+    uint num_lights,
+    uint num_analytic_lights
+)
+{
+    if (num_lights == 0 || cos_light <= 0.0f || light_total_power <= 0.0f)
+    {
+        return 0.0f;
+    }
+    float strategy_probability = 1.0f / (float)light_strategy_count(num_lights, num_analytic_lights);
+    return strategy_probability * luminance(emission) * 2.0f * PI * light_distance * light_distance / (light_total_power * cos_light);
+}
+
+// sample a direction towards a light source from a point on a surface, or inside a medium when the normal is zero
+// returns the radiance arriving along the direction, the pdf is over solid angle,
+// for delta lights it only holds the probability of picking the light
+// lights that bsdf sampling can not find, including distant disks, are reported as delta lights
+float3 sample_light(
+    float3* hit_point,
+    float3* normal,
+    uint* seed,
+    float3* light_direction,
+    float* light_distance,
+    float* light_pdf,
+    bool* delta_light,
+    uint num_lights,
+    float3* light_vertices,
+    float3* light_emissions,
+    float* light_areas,
+    float* light_cdf,
+    uint num_point_lights,
+    struct point_light* point_lights,
+    uint num_spot_lights,
+    struct spot_light* spot_lights,
+    uint num_directional_lights,
+    struct directional_light* directional_lights,
+    float env_rotation,
+    float env_intensity,
+    uint env_map_width,
+    uint env_map_height,
+    float4* env_map_pixels,
+    float* env_map_marginal_cdf,
+    float* env_map_conditional_cdf
+)
+{
+    // each strategy only returns its own light so the chosen strategy is weighted by its probability
+    uint num_analytic_lights = num_point_lights + num_spot_lights + num_directional_lights;
+    uint num_strategies = light_strategy_count(num_lights, num_analytic_lights);
+    float strategy_probability = 1.0f / (float)num_strategies;
+    uint strategy = min((uint)(random_float(seed) * (float)num_strategies), num_strategies - 1);
+    *delta_light = false;
+
+    if (strategy > 0 && num_analytic_lights > 0 && (strategy == 2 || num_lights == 0))
+    {
+        *delta_light = true;
+        // analytic lights are picked uniformly
+        uint light_idx = min((uint)(random_float(seed) * (float)num_analytic_lights), num_analytic_lights - 1);
+        float3 radiance;
+        if (light_idx < num_point_lights)
+        {
+            struct point_light light = point_lights[light_idx];
+            radiance = sample_point_light(hit_point, &light, light_direction, light_distance, light_pdf);
+        }
+        else if (light_idx < num_point_lights + num_spot_lights)
+        {
+            struct spot_light light = spot_lights[light_idx - num_point_lights];
+            radiance = sample_spot_light(hit_point, &light, light_direction, light_distance, light_pdf);
+        }
+        else
+        {
+            struct directional_light light = directional_lights[light_idx - num_point_lights - num_spot_lights];
+            radiance = sample_directional_light(seed, &light, light_direction, light_distance, light_pdf);
+        }
+        *light_pdf *= strategy_probability / (float)num_analytic_lights;
+        return radiance;
+    }
+
+    if (strategy > 0)
+    {
+        float3 radiance = sample_area_light(
+            hit_point,
+            seed,
+            num_lights,
+            light_vertices,
+            light_emissions,
+            light_areas,
+            light_cdf,
+            light_direction,
+            light_distance,
+            light_pdf);
+        *light_pdf *= strategy_probability;
+        return radiance;
+    }
+
+    *light_distance = 1e30;
+
+    // the environment map is sampled by brightness
+    if (env_map_width > 0)
+    {
+        float3 radiance = sample_environment(
+            seed,
+            env_rotation,
+            env_intensity,
+            env_map_width,
+            env_map_height,
+            env_map_pixels,
+            env_map_marginal_cdf,
+            env_map_conditional_cdf,
+            light_direction,
+            light_pdf);
+        *light_pdf *= strategy_probability;
+        return radiance;
+    }
+
+    // points inside a medium receive light from every direction, so the sky is sampled over the whole sphere
+    if (dot(*normal, *normal) <= 0.0f)
+    {
+        *light_direction = random_direction(seed);
+        *light_pdf = 0.25f * INV_PI * strategy_probability;
+        return sky_color(light_direction) * env_intensity;
+    }
+
+    // sample the sky cosine weighted over the hemisphere
+    *light_direction = random_cosine_hemisphere_direction(normal, seed);
+    *light_pdf = max(dot(*normal, *light_direction), 0.0f) * INV_PI * strategy_probability;
+    return sky_color(light_direction) * env_intensity;
+}
@@ -0,0 +1,69 @@
+#pragma once
+
+// material.cl
+// Contains all code related to looking up material properties
+
+// unpack a color that was packed to bytes on the host
+float3 unpack_color(uint color)
+{
+    return (float3)(
+        (float)((color >> 16) & 255),
+        (float)((color >> 8) & 255),
+        (float)(color & 255)
+    ) / 255.0f;
+}
+
+// get the color of the material with the given index, the index of a hit is found by intersect_scene
+float3 material_color(
+    uint mat_idx,
+    uint* mat_offsets,
+    uint* mat_colors
+)
+{
+    return unpack_color(mat_colors[mat_offsets[mat_idx]]);
+}
+
+// get the reflectiveness of the material with the given index, 0 is fully diffuse and 1 a perfect mirror
+float material_reflectiveness(
+    uint mat_idx,
+    uint* mat_offsets,
+    uchar* mat_reflectiveness
+)
+{
+    return (float)mat_reflectiveness[mat_offsets[mat_idx]] / 255.0f;
+}
+
+// get the refraction index of the material with the given index, opaque materials have an index of 0
+float material_refraction_index(
+    uint mat_idx,
+    uint* mat_offsets,
+    float* mat_refraction_indices
+)
+{
+    return mat_refraction_indices[mat_offsets[mat_idx]];
+}
+
+// get the radiance emitted by the material with the given index
+float3 material_emission(
+    uint mat_idx,
+    float3* mat_emissions
+)
+{
+    return mat_emissions[mat_idx];
+}
+
+// get the microfacet parameters of the material with the given index
+void material_microfacet(
+    uint mat_idx,
+    float* mat_roughness,
+    float* mat_metallic,
+    float* mat_specular,
+    float* roughness,
+    float* metallic,
+    float* specular
+)
+{
+    *roughness = mat_roughness[mat_idx];
+    *metallic = mat_metallic[mat_idx];
+    *specular = mat_specular[mat_idx];
+}
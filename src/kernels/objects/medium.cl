@@ -0,0 +1,120 @@
+#pragma once
+#include "src/kernels/tools/constants.cl"
+#include "src/kernels/tools/random.cl"
+
+// medium.cl
+// Contains all code related to homogeneous participating media
+// rays remember the medium of the closed mesh they travel through, outside of those meshes the fog is used
+// media can not be nested, leaving a medium always returns to the fog
+
+// keep in sync with the medium struct in medium.rs
+struct medium
+{
+    float3 absorption;
+    float3 scattering;
+    float anisotropy;
+};
+
+// find the medium a ray travels through and the part of the ray that lies inside of it
+bool medium_interval(
+    uint medium_idx,
+    float3* origin,
+    float3* direction,
+    float max_t,
+    struct medium* media,
+    uint fog_medium,
+    float3 fog_min_bound,
+    float3 fog_max_bound,
+    struct medium* ray_medium,
+    float* t_start,
+    float* t_end
+)
+{
+    if (medium_idx != MAX_UINT)
+    {
+        *ray_medium = media[medium_idx];
+        *t_start = 0.0f;
+        *t_end = max_t;
+        return true;
+    }
+
+    if (fog_medium == MAX_UINT)
+    {
+        return false;
+    }
+
+    // the fog only fills its bounds
+    float3 inv_direction = 1.0f / *direction;
+    float3 t0 = (fog_min_bound - *origin) * inv_direction;
+    float3 t1 = (fog_max_bound - *origin) * inv_direction;
+    float3 t_near = fmin(t0, t1);
+    float3 t_far = fmax(t0, t1);
+    *t_start = max(max(t_near.x, t_near.y), max(t_near.z, 0.0f));
+    *t_end = min(min(t_far.x, t_far.y), min(t_far.z, max_t));
+    if (*t_start >= *t_end)
+    {
+        return false;
+    }
+
+    *ray_medium = media[fog_medium];
+    return true;
+}
+
+// fraction of the light that passes through a part of a ray without being absorbed or scattered away
+float3 medium_transmittance(
+    uint medium_idx,
+    float3* origin,
+    float3* direction,
+    float max_t,
+    struct medium* media,
+    uint fog_medium,
+    float3 fog_min_bound,
+    float3 fog_max_bound
+)
+{
+    struct medium medium;
+    float t_start;
+    float t_end;
+    if (!medium_interval(medium_idx, origin, direction, max_t, media, fog_medium, fog_min_bound, fog_max_bound, &medium, &t_start, &t_end))
+    {
+        return (float3)1;
+    }
+    return exp(-(medium.absorption + medium.scattering) * (t_end - t_start));
+}
+
+// henyey-greenstein phase function, the cosine is between the direction before and after scattering
+float henyey_greenstein(float cos_theta, float anisotropy)
+{
+    float g2 = anisotropy * anisotropy;
+    float denominator = 1.0f + g2 - 2.0f * anisotropy * cos_theta;
+    return (1.0f - g2) / (4.0f * PI * denominator * sqrt(denominator));
+}
+
+// sample a scattered direction exactly proportional to the phase function, so the sample weight is one
+float3 sample_henyey_greenstein(float3* direction, float anisotropy, uint* seed)
+{
+    float r0 = random_float(seed);
+    float r1 = random_float(seed);
+
+    float cos_theta;
+    if (fabs(anisotropy) < 1e-3f)
+    {
+        cos_theta = 1.0f - 2.0f * r0;
+    }
+    else
+    {
+        float s = (1.0f - anisotropy * anisotropy) / (1.0f - anisotropy + 2.0f * anisotropy * r0);
+        cos_theta = (1.0f + anisotropy * anisotropy - s * s) / (2.0f * anisotropy);
+    }
+    cos_theta = clamp(cos_theta, -1.0f, 1.0f);
+    float sin_theta = sqrt(max(0.0f, 1.0f - cos_theta * cos_theta));
+    float phi = 2.0f * PI * r1;
+
+    // build an orthonormal basis around the direction
+    float3 w = *direction;
+    float3 a = fabs(w.x) > 0.9f ? (float3)(0, 1, 0) : (float3)(1, 0, 0);
+    float3 u = normalize(cross(a, w));
+    float3 v = cross(w, u);
+
+    return normalize(u * (sin_theta * cos(phi)) + v * (sin_theta * sin(phi)) + w * cos_theta);
+}
@@ -3,13 +3,99 @@
 #include "src/kernels/types/mat4.cl"
 #include "src/kernels/objects/triangle.cl"
 #include "src/kernels/objects/bvh.cl"
+#include "src/kernels/objects/tlas.cl"
+#include "src/kernels/objects/material.cl"
+#include "src/kernels/objects/texture.cl"
+#include "src/kernels/objects/medium.cl"
+
+// find the closest hit, the triangle, mesh, object and material that were hit and the geometric normal in world space
+bool intersect_scene_triangle(
+    float* ray_t,
+    float3* ray_origin,
+    float3* ray_direction,
+    uint* ray_tri_idx,
+    uint* ray_prim_idx,
+    uint* ray_mesh_idx,
+    uint* ray_obj_idx,
+    uint* ray_mat_idx,
+    float3* ray_geometric_normal,
+    uint num_objects,
+    uint* obj_mesh_ids,
+    uint* obj_mat_ids,
+    struct mat4* obj_inv_transforms,
+    uint* bvh_offsets,
+    uint* mesh_offsets,
+    float3* bvh_min_bounds,
+    float3* bvh_max_bounds,
+    uint* bvh_tri_counts,
+    uint* bvh_left_firsts,
+    uint* bvh_triangle_offsets,
+    struct triangle* bvh_triangles,
+    uint* mesh_triangle_mat_ids,
+    float3* tlas_min_bounds,
+    float3* tlas_max_bounds,
+    uint* tlas_obj_counts,
+    uint* tlas_left_firsts,
+    uint* tlas_obj_ids
+)
+{
+    *ray_tri_idx = MAX_UINT;
+    *ray_prim_idx = MAX_UINT;
+    *ray_mesh_idx = MAX_UINT;
+    *ray_obj_idx = MAX_UINT;
+
+    if (!traverse_tlas(
+        ray_t,
+        ray_origin,
+        ray_direction,
+        ray_tri_idx,
+        ray_prim_idx,
+        ray_mesh_idx,
+        ray_obj_idx,
+        false,
+        num_objects,
+        obj_mesh_ids,
+        obj_inv_transforms,
+        bvh_offsets,
+        bvh_triangle_offsets,
+        bvh_min_bounds,
+        bvh_max_bounds,
+        bvh_tri_counts,
+        bvh_left_firsts,
+        bvh_triangles,
+        tlas_min_bounds,
+        tlas_max_bounds,
+        tlas_obj_counts,
+        tlas_left_firsts,
+        tlas_obj_ids))
+    {
+        return false; // no intersection
+    }
+
+    struct mat4 obj_inv_transform = obj_inv_transforms[*ray_obj_idx];
+    struct triangle tr = bvh_triangles[bvh_triangle_offsets[*ray_mesh_idx] + *ray_prim_idx];
+    float3 geometric_normal = cross(tr.vertex1 - tr.vertex0, tr.vertex2 - tr.vertex0);
+    *ray_geometric_normal = normalize(transform_normal(&geometric_normal, &obj_inv_transform));
+
+    // triangles without a material of their own use the material of the object
+    uint mat_idx = mesh_triangle_mat_ids[mesh_offsets[*ray_mesh_idx] + *ray_tri_idx];
+    if (mat_idx == MAX_UINT)
+    {
+        mat_idx = obj_mat_ids[*ray_obj_idx];
+    }
+    *ray_mat_idx = mat_idx;
+    return true;
+}
 
 void intersect_scene(
     float* ray_t,
     float3* ray_origin,
     float3* ray_direction,
     float3* ray_normal,
+    float3* ray_geometric_normal,
     float3* intersect_color,
+    uint* ray_obj_idx,
+    uint* ray_mat_idx,
     uint num_objects,
     uint* obj_mesh_ids,
     uint* obj_mat_ids,
@@ -27,37 +113,114 @@ void intersect_scene(
     float3* mesh_vertex_normals,
     uint* mat_offsets,
     uint* mat_colors,
-    uchar* mat_reflectiveness,
-    float* mat_refraction_index
+    float2* mesh_vertex_uvs,
+    uint* mat_albedo_textures,
+    uint* tex_offsets,
+    uint2* tex_dimensions,
+    uint* tex_wrap_modes,
+    uint* tex_pixels,
+    float4* mesh_vertex_tangents,
+    uint* mat_normal_textures,
+    uint* mesh_triangle_mat_ids,
+    float3* tlas_min_bounds,
+    float3* tlas_max_bounds,
+    uint* tlas_obj_counts,
+    uint* tlas_left_firsts,
+    uint* tlas_obj_ids
 )
 {
-    uint ray_tri_idx = MAX_UINT;
-    uint ray_mesh_idx = MAX_UINT;
-    uint ray_obj_idx = MAX_UINT;
+    uint ray_tri_idx;
+    uint ray_prim_idx;
+    uint ray_mesh_idx;
 
-    for (uint i = 0; i < num_objects; i++)
+    if (!intersect_scene_triangle(
+        ray_t,
+        ray_origin,
+        ray_direction,
+        &ray_tri_idx,
+        &ray_prim_idx,
+        &ray_mesh_idx,
+        ray_obj_idx,
+        ray_mat_idx,
+        ray_geometric_normal,
+        num_objects,
+        obj_mesh_ids,
+        obj_mat_ids,
+        obj_inv_transforms,
+        bvh_offsets,
+        mesh_offsets,
+        bvh_min_bounds,
+        bvh_max_bounds,
+        bvh_tri_counts,
+        bvh_left_firsts,
+        bvh_triangle_offsets,
+        bvh_triangles,
+        mesh_triangle_mat_ids,
+        tlas_min_bounds,
+        tlas_max_bounds,
+        tlas_obj_counts,
+        tlas_left_firsts,
+        tlas_obj_ids))
     {
-        uint mesh_idx = obj_mesh_ids[i];
-        struct mat4 obj_inv_transform = obj_inv_transforms[i];
-        float3 new_origin = transform_position(ray_origin, &obj_inv_transform);
-        float3 new_direction = transform_vector(ray_direction, &obj_inv_transform);
+        return;
+    }
 
-        uint bvh_offset = bvh_offsets[mesh_idx];
-        uint triangle_offset = bvh_triangle_offsets[mesh_idx];
+    // compute the hit point in object space to interpolate the vertex normals
+    struct mat4 obj_inv_transform = obj_inv_transforms[*ray_obj_idx];
+    float3 obj_origin = transform_position(ray_origin, &obj_inv_transform);
+    float3 obj_direction = transform_vector(ray_direction, &obj_inv_transform);
+    float3 obj_hit = obj_origin + obj_direction * (*ray_t);
 
-        if (!intersect_bvh(ray_t, &new_origin, &new_direction, &ray_tri_idx, bvh_min_bounds + bvh_offset, bvh_max_bounds + bvh_offset, bvh_tri_counts + bvh_offset, bvh_left_firsts + bvh_offset, bvh_triangles + triangle_offset))
-        {
-            continue;
-        }
+    struct triangle tr = bvh_triangles[bvh_triangle_offsets[ray_mesh_idx] + ray_prim_idx];
+    float2 barycentrics = triangle_barycentrics(&obj_hit, &tr.vertex0, &tr.vertex1, &tr.vertex2);
+
+    uint3 vertex_ids = mesh_vertex_ids[mesh_offsets[ray_mesh_idx] + ray_tri_idx];
+    float w = 1.0f - barycentrics.x - barycentrics.y;
+    float3 normal = mesh_vertex_normals[vertex_ids.x] * w +
+                    mesh_vertex_normals[vertex_ids.y] * barycentrics.x +
+                    mesh_vertex_normals[vertex_ids.z] * barycentrics.y;
+    *ray_normal = normalize(transform_normal(&normal, &obj_inv_transform));
 
-        ray_mesh_idx = ray_mesh_idx;
-        ray_obj_idx = i;
+    uint mat_idx = *ray_mat_idx;
+    *intersect_color = material_color(mat_idx, mat_offsets, mat_colors);
+
+    uint albedo_texture_idx = mat_albedo_textures[mat_idx];
+    uint normal_texture_idx = mat_normal_textures[mat_idx];
+    if (albedo_texture_idx == MAX_UINT && normal_texture_idx == MAX_UINT)
+    {
+        return;
     }
 
-    if (ray_obj_idx == MAX_UINT)
+    float2 uv = mesh_vertex_uvs[vertex_ids.x] * w +
+                mesh_vertex_uvs[vertex_ids.y] * barycentrics.x +
+                mesh_vertex_uvs[vertex_ids.z] * barycentrics.y;
+
+    // textured materials replace the material color with the texture
+    if (albedo_texture_idx != MAX_UINT)
     {
-        return; // no intersection
+        *intersect_color = sample_texture(albedo_texture_idx, uv, tex_offsets, tex_dimensions, tex_wrap_modes, tex_pixels, true).xyz;
     }
 
-    *intersect_color = (float3)1;
+    // the normal map is in tangent space, the bitangent follows from the handedness stored in the tangent
+    if (normal_texture_idx != MAX_UINT)
+    {
+        float4 tangent = mesh_vertex_tangents[vertex_ids.x] * w +
+                         mesh_vertex_tangents[vertex_ids.y] * barycentrics.x +
+                         mesh_vertex_tangents[vertex_ids.z] * barycentrics.y;
+        float3 obj_tangent = tangent.xyz;
+        struct mat4 obj_transform = obj_transforms[*ray_obj_idx];
+        float3 world_tangent = transform_vector(&obj_tangent, &obj_transform);
+
+        float3 n = *ray_normal;
+        float3 t = world_tangent - n * dot(n, world_tangent);
+        if (dot(t, t) <= 0.0f)
+        {
+            return;
+        }
+        t = normalize(t);
+        float3 b = cross(n, t) * (tangent.w < 0.0f ? -1.0f : 1.0f);
+
+        float3 mapped = sample_texture(normal_texture_idx, uv, tex_offsets, tex_dimensions, tex_wrap_modes, tex_pixels, false).xyz * 2.0f - 1.0f;
+        *ray_normal = normalize(t * mapped.x + b * mapped.y + n * max(mapped.z, EPSILON));
+    }
 }
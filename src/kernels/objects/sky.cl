@@ -0,0 +1,11 @@
+#pragma once
+
+// sky.cl
+// Contains all code related to the light of rays that leave the scene
+
+// radiance of the sky in a direction, a simple gradient from the horizon to the zenith
+float3 sky_color(float3* direction)
+{
+    float t = 0.5f * (direction->y + 1.0f);
+    return (1.0f - t) * (float3)(1.0f, 1.0f, 1.0f) + t * (float3)(0.5f, 0.7f, 1.0f);
+}
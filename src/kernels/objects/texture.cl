@@ -0,0 +1,75 @@
+#pragma once
+#include "src/kernels/tools/color.cl"
+#include "src/kernels/objects/material.cl"
+
+// texture.cl
+// Contains all code related to sampling the textures, which are packed after each other in one buffer
+
+// wrap modes, keep in sync with WrapMode in texture.rs
+#define WRAP_MODE_REPEAT 0
+#define WRAP_MODE_CLAMP 1
+
+// map a texel coordinate that lies outside of the texture back into it
+int wrap_coordinate(int coordinate, int size, uint wrap_mode)
+{
+    switch (wrap_mode)
+    {
+        case WRAP_MODE_CLAMP:
+            return clamp(coordinate, 0, size - 1);
+        default:
+        {
+            int wrapped = coordinate % size;
+            return wrapped < 0 ? wrapped + size : wrapped;
+        }
+    }
+}
+
+// read a single texel, colors are converted to linear while data like normals is used as it is stored
+float4 texture_texel(uint* pixels, uint2 dimensions, int x, int y, uint wrap_mode, bool decode_srgb)
+{
+    x = wrap_coordinate(x, (int)dimensions.x, wrap_mode);
+    y = wrap_coordinate(y, (int)dimensions.y, wrap_mode);
+    uint texel = pixels[y * dimensions.x + x];
+    float3 color = unpack_color(texel);
+    if (decode_srgb)
+    {
+        color = srgb_decode(color);
+    }
+    return (float4)(color, (float)(texel >> 24) / 255.0f);
+}
+
+// bilinearly filtered lookup, the v coordinate points up so the rows are flipped
+float4 sample_texture(
+    uint texture_idx,
+    float2 uv,
+    uint* tex_offsets,
+    uint2* tex_dimensions,
+    uint* tex_wrap_modes,
+    uint* tex_pixels,
+    bool decode_srgb
+)
+{
+    uint2 dimensions = tex_dimensions[texture_idx];
+    uint wrap_mode = tex_wrap_modes[texture_idx];
+    uint* pixels = tex_pixels + tex_offsets[texture_idx];
+
+    // texel centers lie at half coordinates
+    float x = uv.x * (float)dimensions.x - 0.5f;
+    float y = (1.0f - uv.y) * (float)dimensions.y - 0.5f;
+    float x0 = floor(x);
+    float y0 = floor(y);
+    float fx = x - x0;
+    float fy = y - y0;
+
+    int ix = (int)x0;
+    int iy = (int)y0;
+    float4 top = mix(
+        texture_texel(pixels, dimensions, ix, iy, wrap_mode, decode_srgb),
+        texture_texel(pixels, dimensions, ix + 1, iy, wrap_mode, decode_srgb),
+        fx);
+    float4 bottom = mix(
+        texture_texel(pixels, dimensions, ix, iy + 1, wrap_mode, decode_srgb),
+        texture_texel(pixels, dimensions, ix + 1, iy + 1, wrap_mode, decode_srgb),
+        fx);
+    return mix(top, bottom, fy);
+}
@@ -0,0 +1,188 @@
+#pragma once
+#include "src/kernels/objects/aabb.cl"
+#include "src/kernels/objects/bvh.cl"
+#include "src/kernels/types/mat4.cl"
+
+// tlas.cl
+// Contains the traversal of the top level bvh over the world space bounds of the objects
+// every object a ray reaches is traced with the bvh of its mesh in the space of the object,
+// the ray is not normalized in object space so the distances stay the same as in world space
+
+// find the closest hit over all objects, or any hit when only occlusion is needed
+bool traverse_tlas(
+    float* ray_t,
+    float3* ray_origin,
+    float3* ray_direction,
+    uint* ray_tri_idx,
+    uint* ray_prim_idx,
+    uint* ray_mesh_idx,
+    uint* ray_obj_idx,
+    bool any_hit,
+    uint num_objects,
+    uint* obj_mesh_ids,
+    struct mat4* obj_inv_transforms,
+    uint* bvh_offsets,
+    uint* bvh_triangle_offsets,
+    float3* bvh_min_bounds,
+    float3* bvh_max_bounds,
+    uint* bvh_tri_counts,
+    uint* bvh_left_firsts,
+    struct triangle* bvh_triangles,
+    float3* tlas_min_bounds,
+    float3* tlas_max_bounds,
+    uint* tlas_obj_counts,
+    uint* tlas_left_firsts,
+    uint* tlas_obj_ids
+)
+{
+    if (num_objects == 0)
+    {
+        return false;
+    }
+
+    {
+        float3 min_bound = tlas_min_bounds[0];
+        float3 max_bound = tlas_max_bounds[0];
+        if (intersect_aabb(ray_t, ray_origin, ray_direction, &min_bound, &max_bound) == 1e30)
+        {
+            return false;
+        }
+    }
+
+    uint node = 0;
+    uint stack[64];
+    uint stack_ptr = 0;
+    bool intersected = false;
+
+    while (1)
+    {
+        uint obj_count = tlas_obj_counts[node];
+        uint left_first = tlas_left_firsts[node];
+        if (obj_count > 0)
+        {
+            for (uint i = 0; i < obj_count; i++)
+            {
+                uint obj_idx = tlas_obj_ids[left_first + i];
+                uint mesh_idx = obj_mesh_ids[obj_idx];
+                struct mat4 obj_inv_transform = obj_inv_transforms[obj_idx];
+                float3 obj_origin = transform_position(ray_origin, &obj_inv_transform);
+                float3 obj_direction = transform_vector(ray_direction, &obj_inv_transform);
+
+                uint bvh_offset = bvh_offsets[mesh_idx];
+                uint triangle_offset = bvh_triangle_offsets[mesh_idx];
+
+                if (any_hit)
+                {
+                    if (occlude_bvh(ray_t, &obj_origin, &obj_direction, bvh_min_bounds + bvh_offset, bvh_max_bounds + bvh_offset, bvh_tri_counts + bvh_offset, bvh_left_firsts + bvh_offset, bvh_triangles + triangle_offset))
+                    {
+                        return true;
+                    }
+                }
+                else if (intersect_bvh(ray_t, &obj_origin, &obj_direction, ray_tri_idx, ray_prim_idx, bvh_min_bounds + bvh_offset, bvh_max_bounds + bvh_offset, bvh_tri_counts + bvh_offset, bvh_left_firsts + bvh_offset, bvh_triangles + triangle_offset))
+                {
+                    *ray_mesh_idx = mesh_idx;
+                    *ray_obj_idx = obj_idx;
+                    intersected = true;
+                }
+            }
+
+            if (stack_ptr == 0)
+            {
+                break;
+            }
+            node = stack[--stack_ptr];
+            continue;
+        }
+
+        // visit the closest child first, the other one is only visited when the ray gets that far
+        uint child1 = left_first;
+        uint child2 = left_first + 1;
+
+        float3 child1_min_bound = tlas_min_bounds[child1];
+        float3 child2_min_bound = tlas_min_bounds[child2];
+        float3 child1_max_bound = tlas_max_bounds[child1];
+        float3 child2_max_bound = tlas_max_bounds[child2];
+
+        float dist1 = intersect_aabb(ray_t, ray_origin, ray_direction, &child1_min_bound, &child1_max_bound);
+        float dist2 = intersect_aabb(ray_t, ray_origin, ray_direction, &child2_min_bound, &child2_max_bound);
+
+        if (dist1 > dist2)
+        {
+            float tmp = dist1;
+            dist1 = dist2;
+            dist2 = tmp;
+            uint tmp2 = child1;
+            child1 = child2;
+            child2 = tmp2;
+        }
+
+        if (dist1 == 1e30)
+        {
+            if (stack_ptr == 0)
+            {
+                break;
+            }
+            node = stack[--stack_ptr];
+            continue;
+        }
+
+        node = child1;
+        if (dist2 != 1e30)
+        {
+            stack[stack_ptr++] = child2;
+        }
+    }
+
+    return intersected;
+}
+
+bool occlude_meshes(
+    float* ray_t,
+    float3* ray_origin,
+    float3* ray_direction,
+    uint num_objects,
+    uint* obj_mesh_ids,
+    struct mat4* obj_inv_transforms,
+    uint* mesh_offsets,
+    uint* mesh_triangle_offsets,
+    float3* mesh_min_bounds,
+    float3* mesh_max_bounds,
+    uint* mesh_tri_counts,
+    uint* mesh_left_firsts,
+    struct triangle* mesh_triangles,
+    float3* tlas_min_bounds,
+    float3* tlas_max_bounds,
+    uint* tlas_obj_counts,
+    uint* tlas_left_firsts,
+    uint* tlas_obj_ids
+    )
+{
+    uint tri_idx;
+    uint prim_idx;
+    uint mesh_idx;
+    uint obj_idx;
+    return traverse_tlas(
+        ray_t,
+        ray_origin,
+        ray_direction,
+        &tri_idx,
+        &prim_idx,
+        &mesh_idx,
+        &obj_idx,
+        true,
+        num_objects,
+        obj_mesh_ids,
+        obj_inv_transforms,
+        mesh_offsets,
+        mesh_triangle_offsets,
+        mesh_min_bounds,
+        mesh_max_bounds,
+        mesh_tri_counts,
+        mesh_left_firsts,
+        mesh_triangles,
+        tlas_min_bounds,
+        tlas_max_bounds,
+        tlas_obj_counts,
+        tlas_left_firsts,
+        tlas_obj_ids);
+}
@@ -50,3 +50,24 @@ bool intersect_triangle(
     }
     return false;
 }
+
+// Compute the barycentric coordinates of a point that lies on the triangle
+float2 triangle_barycentrics(
+    float3* point,
+    float3* vertex0,
+    float3* vertex1,
+    float3* vertex2)
+{
+    float3 edge1 = *vertex1 - *vertex0;
+    float3 edge2 = *vertex2 - *vertex0;
+    float3 p = *point - *vertex0;
+    float d00 = dot(edge1, edge1);
+    float d01 = dot(edge1, edge2);
+    float d11 = dot(edge2, edge2);
+    float d20 = dot(p, edge1);
+    float d21 = dot(p, edge2);
+    float inv_denom = 1.0f / (d00 * d11 - d01 * d01);
+    float v = (d11 * d20 - d01 * d21) * inv_denom;
+    float w = (d00 * d21 - d01 * d20) * inv_denom;
+    return (float2)(v, w);
+}
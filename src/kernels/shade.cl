@@ -1,6 +1,444 @@
+#include "src/kernels/objects/scene.cl"
+#include "src/kernels/objects/material.cl"
+#include "src/kernels/objects/environment.cl"
+#include "src/kernels/objects/lights.cl"
+#include "src/kernels/tools/random.cl"
+#include "src/kernels/tools/color.cl"
+#include "src/kernels/tools/ray_tracing.cl"
+#include "src/kernels/tools/microfacet.cl"
+#include "src/kernels/tools/mis.cl"
 
+// shade.cl
+// Shades the hits of a bounce, writes the gathered light to the light layer of the bounce
+// and appends the continuation rays to the queue of the next bounce
+// every diffuse hit also emits one shadow ray towards a sampled light, which is traced by connect
+// mirrors and dielectrics continue the path along a single specular direction instead
+// rays that travel through a medium can scatter before they reach their hit, which is then ignored
+// lights found by the shadow rays and by the continuation rays are combined with multiple importance sampling
 
-__kernel void shade()
+// the smallest cosine between the shading normal and the view direction
+#define SHADING_NORMAL_MIN_COS 0.01f
+
+__kernel void shade(
+    uint bounce,
+    uint num_bounces,
+    uint num_primary_rays,
+    uint glob_seed,
+    uint russian_roulette_start,
+    float russian_roulette_max_survival,
+    __global uint* num_rays,
+    __global uint* ray_write_back_ids,
+    __global float* ray_ts,
+    __global float3* ray_origins,
+    __global float3* ray_directions,
+    __global float3* ray_normals,
+    __global uint* ray_obj_ids,
+    __global float3* ray_energies,
+    __global float3* light,
+    __global float* shadow_ray_ts,
+    __global float3* shadow_ray_origins,
+    __global float3* shadow_ray_directions,
+    __global uint* shadow_ray_write_back_ids,
+    __global float3* shadow_ray_write_back_lights,
+    __global uint* ray_flags,
+    float env_rotation,
+    float env_intensity,
+    __global float3* ray_intersection_colors,
+    __global float3* ray_geometric_normals,
+    __global uint* ray_mat_ids,
+    __global uint* ray_media,
+    __global uint* shadow_ray_media,
+    uint sampling_strategy,
+    __global float* ray_bsdf_pdfs,
+    __global float* ray_environment_pdfs,
+    __global uint* mat_offsets,
+    __global uchar* mat_reflectiveness,
+    __global float* mat_refraction_index,
+    __global float3* mat_emissions,
+    uint num_lights,
+    __global float3* light_vertices,
+    __global float3* light_emissions,
+    __global float* light_areas,
+    __global float* light_cdf,
+    uint num_point_lights,
+    __global struct point_light* point_lights,
+    uint num_spot_lights,
+    __global struct spot_light* spot_lights,
+    uint num_directional_lights,
+    __global struct directional_light* directional_lights,
+    uint env_map_width,
+    uint env_map_height,
+    __global float4* env_map_pixels,
+    __global float* env_map_marginal_cdf,
+    __global float* env_map_conditional_cdf,
+    __global float* mat_roughness,
+    __global float* mat_metallic,
+    __global float* mat_specular,
+    __global uint* mat_media,
+    __global struct medium* media,
+    uint fog_medium,
+    float3 fog_min_bound,
+    float3 fog_max_bound,
+    float light_total_power
+)
 {
+    uint idx = get_global_id(0);
+
+    if (idx >= num_rays[bounce * 2])
+    {
+        return;
+    }
+
+    uint ray_idx = (bounce & 1) * num_primary_rays + idx;
+    uint write_back_idx = ray_write_back_ids[ray_idx];
+    uint light_idx = bounce * num_primary_rays + write_back_idx;
+
+    float3 ray_origin = ray_origins[ray_idx];
+    float3 ray_direction = ray_directions[ray_idx];
+    float3 ray_energy = ray_energies[ray_idx];
+    uint obj_idx = ray_obj_ids[ray_idx];
+    uint mat_idx = ray_mat_ids[ray_idx];
+    uint flags = ray_flags[ray_idx];
+    uint medium_idx = ray_media[ray_idx];
+
+    uint seed = init_seed(glob_seed ^ wang_hash(light_idx));
+
+    float3 new_origin;
+    float3 new_direction;
+    bool refracted = false;
+
+    // the pdfs that sample_light and the bsdf have for the continuation ray,
+    // a ray that runs into a light uses them to weight the light against the shadow ray that found it
+    uint num_analytic_lights = num_point_lights + num_spot_lights + num_directional_lights;
+    float bsdf_pdf = 0.0f;
+    float env_light_pdf = 0.0f;
+
+    // free flight through the medium the ray travels in, the distance is sampled with the average extinction
+    // and the sample weight corrects for the extinction of every color channel
+    struct medium medium;
+    float medium_start;
+    float medium_end;
+    bool scattered = false;
+    if (medium_interval(medium_idx, &ray_origin, &ray_direction, ray_ts[ray_idx], media, fog_medium, fog_min_bound, fog_max_bound, &medium, &medium_start, &medium_end))
+    {
+        float3 extinction = medium.absorption + medium.scattering;
+        float sampling_extinction = (extinction.x + extinction.y + extinction.z) / 3.0f;
+        if (sampling_extinction > 0.0f)
+        {
+            float distance = -log(1.0f - random_float(&seed)) / sampling_extinction;
+            if (distance < medium_end - medium_start)
+            {
+                scattered = true;
+                ray_energy *= medium.scattering * exp(-(extinction - sampling_extinction) * distance) / sampling_extinction;
+                new_origin = ray_origin + ray_direction * (medium_start + distance);
+            }
+            else
+            {
+                ray_energy *= exp(-(extinction - sampling_extinction) * (medium_end - medium_start));
+            }
+        }
+    }
+
+    if (scattered)
+    {
+        // scattering only gathers light through its shadow ray
+        if (bounce >= num_bounces)
+        {
+            return;
+        }
+
+        // next event estimation with the phase function in place of the bsdf,
+        // a point inside a medium has no normal
+        float3 no_normal = (float3)0;
+        float3 light_direction;
+        float light_distance;
+        float light_pdf;
+        bool delta_light;
+        float3 light_radiance = sample_light(
+            &new_origin,
+            &no_normal,
+            &seed,
+            &light_direction,
+            &light_distance,
+            &light_pdf,
+            &delta_light,
+            num_lights,
+            light_vertices,
+            light_emissions,
+            light_areas,
+            light_cdf,
+            num_point_lights,
+            point_lights,
+            num_spot_lights,
+            spot_lights,
+            num_directional_lights,
+            directional_lights,
+            env_rotation,
+            env_intensity,
+            env_map_width,
+            env_map_height,
+            env_map_pixels,
+            env_map_marginal_cdf,
+            env_map_conditional_cdf);
+        float phase = henyey_greenstein(dot(ray_direction, light_direction), medium.anisotropy);
+        float light_weight = light_sample_weight(sampling_strategy, light_pdf, phase, delta_light);
+        if (light_pdf > 0.0f && light_weight > 0.0f)
+        {
+            uint shadow_idx = atomic_inc(&num_rays[bounce * 2 + 1]);
+            shadow_ray_ts[shadow_idx] = light_distance - 2.0f * EPSILON;
+            shadow_ray_origins[shadow_idx] = new_origin;
+            shadow_ray_directions[shadow_idx] = light_direction;
+            shadow_ray_write_back_ids[shadow_idx] = write_back_idx;
+            shadow_ray_write_back_lights[shadow_idx] = ray_energy * light_radiance * (phase * light_weight / light_pdf);
+            shadow_ray_media[shadow_idx] = medium_idx;
+        }
+
+        // the phase function is sampled exactly, the medium stays the same
+        new_direction = sample_henyey_greenstein(&ray_direction, medium.anisotropy, &seed);
+        bsdf_pdf = henyey_greenstein(dot(ray_direction, new_direction), medium.anisotropy);
+        env_light_pdf = environment_light_pdf(
+            &no_normal,
+            &new_direction,
+            num_lights,
+            num_analytic_lights,
+            env_rotation,
+            env_map_width,
+            env_map_height,
+            env_map_marginal_cdf,
+            env_map_conditional_cdf);
+        flags &= ~RAY_FLAG_SPECULAR;
+    }
+    else
+    {
+        // after a diffuse bounce or scattering the lights are also found through the shadow rays,
+        // so the light that the ray runs into is weighted against them
+        bool weighted = bounce > 0 && !(flags & RAY_FLAG_SPECULAR);
+
+        // rays that leave the scene gather the light of the environment
+        if (obj_idx == MAX_UINT)
+        {
+            float weight = weighted ? bsdf_sample_weight(sampling_strategy, ray_bsdf_pdfs[ray_idx], ray_environment_pdfs[ray_idx]) : 1.0f;
+            if (weight > 0.0f)
+            {
+                light[light_idx] += ray_energy * weight * environment_radiance(
+                    &ray_direction,
+                    env_rotation,
+                    env_intensity,
+                    env_map_width,
+                    env_map_height,
+                    env_map_pixels);
+            }
+            return;
+        }
+
+        // emitters add their light
+        float3 emission = material_emission(mat_idx, mat_emissions);
+        if (emission.x + emission.y + emission.z > 0.0f)
+        {
+            float weight = 1.0f;
+            if (weighted)
+            {
+                float cos_light = fabs(dot(ray_geometric_normals[ray_idx], ray_direction));
+                float light_pdf = area_light_pdf(emission, ray_ts[ray_idx], cos_light, light_total_power, num_lights, num_analytic_lights);
+                weight = bsdf_sample_weight(sampling_strategy, ray_bsdf_pdfs[ray_idx], light_pdf);
+            }
+            light[light_idx] += ray_energy * emission * weight;
+        }
+
+        // the last bounce only gathers light
+        if (bounce >= num_bounces)
+        {
+            return;
+        }
+
+        float reflectiveness = material_reflectiveness(mat_idx, mat_offsets, mat_reflectiveness);
+        float refraction_index = material_refraction_index(mat_idx, mat_offsets, mat_refraction_index);
+        uint mat_medium = mat_media[mat_idx];
+        bool medium_boundary = mat_medium != MAX_UINT && refraction_index <= 0.0f;
+
+        // the albedo is looked up at the hit, including textures
+        float3 albedo = ray_intersection_colors[ray_idx];
+        if (!medium_boundary && albedo.x + albedo.y + albedo.z <= 0.0f)
+        {
+            return;
+        }
+
+        // the geometric normal decides the side of the surface, the shading normal is flipped to the same side
+        float3 geometric_normal = ray_geometric_normals[ray_idx];
+        bool entering = dot(geometric_normal, ray_direction) < 0.0f;
+        if (!entering)
+        {
+            geometric_normal = -geometric_normal;
+        }
+
+        float3 ray_normal = ray_normals[ray_idx];
+        if (dot(ray_normal, geometric_normal) < 0.0f)
+        {
+            ray_normal = -ray_normal;
+        }
+
+        // interpolated and mapped normals can face away from the viewer,
+        // bend them back towards it so the bsdf never sees the surface from behind
+        float3 wo = -ray_direction;
+        float cos_view = dot(ray_normal, wo);
+        if (cos_view < SHADING_NORMAL_MIN_COS)
+        {
+            ray_normal = normalize(ray_normal + wo * (SHADING_NORMAL_MIN_COS - cos_view));
+        }
+
+        float3 hit_point = ray_origin + ray_direction * ray_ts[ray_idx];
+        new_origin = hit_point + geometric_normal * EPSILON;
+
+        if (medium_boundary)
+        {
+            // the surface only bounds a medium, the ray passes through unchanged and keeps its flags
+            new_direction = ray_direction;
+            new_origin = hit_point - geometric_normal * EPSILON;
+            medium_idx = entering ? mat_medium : MAX_UINT;
+            refracted = true;
+            bsdf_pdf = ray_bsdf_pdfs[ray_idx];
+            env_light_pdf = ray_environment_pdfs[ray_idx];
+        }
+        else if (refraction_index > 0.0f)
+        {
+            // dielectric, the fresnel term decides between reflection and refraction,
+            // under total internal reflection it is always one
+            // the side comes from the geometry, the flag goes wrong on open meshes and overlapping glass
+            bool inside = !entering;
+            float n1 = inside ? refraction_index : 1.0f;
+            float n2 = inside ? 1.0f : refraction_index;
+            float reflect_amount = fresnel_reflect_amount(n1, n2, ray_normal, ray_direction, reflectiveness);
+
+            if (random_float(&seed) < reflect_amount)
+            {
+                new_direction = reflect(ray_direction, ray_normal);
+            }
+            else
+            {
+                // the medium absorbs the color of the material on the way through,
+                // the medium of the material fills the inside
+                new_direction = normalize(refract(ray_direction, ray_normal, n1 / n2));
+                new_origin = hit_point - geometric_normal * EPSILON;
+                flags = entering ? (flags | RAY_FLAG_INSIDE) : (flags & ~RAY_FLAG_INSIDE);
+                medium_idx = entering ? mat_medium : MAX_UINT;
+                refracted = true;
+                ray_energy *= albedo;
+            }
+            flags |= RAY_FLAG_SPECULAR;
+        }
+        else if (reflectiveness > 0.0f && random_float(&seed) < reflectiveness)
+        {
+            // mirror, tinted by the color of the material
+            new_direction = reflect(ray_direction, ray_normal);
+            flags |= RAY_FLAG_SPECULAR;
+            ray_energy *= albedo;
+        }
+        else
+        {
+            // glossy and diffuse surfaces are shaded with the microfacet bsdf
+            float roughness;
+            float metallic;
+            float specular;
+            material_microfacet(mat_idx, mat_roughness, mat_metallic, mat_specular, &roughness, &metallic, &specular);
+
+            // next event estimation, the shadow ray carries the light it would add when it is not occluded
+            float3 light_direction;
+            float light_distance;
+            float light_pdf;
+            bool delta_light;
+            float3 light_radiance = sample_light(
+                &hit_point,
+                &ray_normal,
+                &seed,
+                &light_direction,
+                &light_distance,
+                &light_pdf,
+                &delta_light,
+                num_lights,
+                light_vertices,
+                light_emissions,
+                light_areas,
+                light_cdf,
+                num_point_lights,
+                point_lights,
+                num_spot_lights,
+                spot_lights,
+                num_directional_lights,
+                directional_lights,
+                env_rotation,
+                env_intensity,
+                env_map_width,
+                env_map_height,
+                env_map_pixels,
+                env_map_marginal_cdf,
+                env_map_conditional_cdf);
+            float cos_light = dot(ray_normal, light_direction);
+            float light_bsdf_pdf;
+            float3 bsdf = microfacet_evaluate(&ray_normal, &wo, &light_direction, &albedo, roughness, metallic, specular, &light_bsdf_pdf);
+            float light_weight = light_sample_weight(sampling_strategy, light_pdf, light_bsdf_pdf, delta_light);
+            if (cos_light > 0.0f && light_pdf > 0.0f && light_weight > 0.0f && dot(geometric_normal, light_direction) > 0.0f)
+            {
+                uint shadow_idx = atomic_inc(&num_rays[bounce * 2 + 1]);
+                shadow_ray_ts[shadow_idx] = light_distance - 2.0f * EPSILON;
+                shadow_ray_origins[shadow_idx] = new_origin;
+                shadow_ray_directions[shadow_idx] = light_direction;
+                shadow_ray_write_back_ids[shadow_idx] = write_back_idx;
+                shadow_ray_write_back_lights[shadow_idx] = ray_energy * bsdf * light_radiance * (cos_light * light_weight / light_pdf);
+                shadow_ray_media[shadow_idx] = medium_idx;
+            }
+
+            // the sample weight is the bsdf times the cosine over the pdf
+            float3 sample_weight = microfacet_sample(&ray_normal, &wo, &albedo, roughness, metallic, specular, &seed, &new_direction, &bsdf_pdf);
+            if (bsdf_pdf <= 0.0f)
+            {
+                return;
+            }
+            ray_energy *= sample_weight;
+            env_light_pdf = environment_light_pdf(
+                &ray_normal,
+                &new_direction,
+                num_lights,
+                num_analytic_lights,
+                env_rotation,
+                env_map_width,
+                env_map_height,
+                env_map_marginal_cdf,
+                env_map_conditional_cdf);
+
+            // the light of the next hit is weighted against the shadow ray
+            flags &= ~RAY_FLAG_SPECULAR;
+        }
+
+        // continuation rays have to leave on the side of the geometric surface they were sampled for,
+        // otherwise they would leak through the surface
+        if ((dot(new_direction, geometric_normal) > 0.0f) == refracted)
+        {
+            return;
+        }
+    }
+
+    // russian roulette, paths with little energy are likely to be terminated,
+    // survivors are compensated by the survival probability to keep the estimate unbiased
+    if (bounce >= russian_roulette_start)
+    {
+        float survival = min(luminance(ray_energy), russian_roulette_max_survival);
+        if (survival <= 0.0f || random_float(&seed) >= survival)
+        {
+            return;
+        }
+        ray_energy /= survival;
+    }
+
+    // append the continuation ray to the queue of the next bounce
+    uint next_bounce = bounce + 1;
+    uint next_idx = ((next_bounce & 1) * num_primary_rays) + atomic_inc(&num_rays[next_bounce * 2]);
 
-}
\ No newline at end of file
+    ray_write_back_ids[next_idx] = write_back_idx;
+    ray_origins[next_idx] = new_origin;
+    ray_directions[next_idx] = new_direction;
+    ray_energies[next_idx] = ray_energy;
+    ray_flags[next_idx] = flags;
+    ray_media[next_idx] = medium_idx;
+    ray_bsdf_pdfs[next_idx] = bsdf_pdf;
+    ray_environment_pdfs[next_idx] = env_light_pdf;
+}
@@ -0,0 +1,95 @@
+#pragma once
+
+// color.cl
+// Contains all code related to transforming rendered light to display colors
+
+// tone mapping operators, keep in sync with ToneMapping in renderer.rs
+#define TONE_MAPPING_LINEAR 0
+#define TONE_MAPPING_REINHARD 1
+#define TONE_MAPPING_ACES 2
+#define TONE_MAPPING_AGX 3
+
+float luminance(float3 color)
+{
+    return dot(color, (float3)(0.2126f, 0.7152f, 0.0722f));
+}
+
+// ACES filmic curve fitted by Krzysztof Narkowicz
+float3 aces_filmic(float3 x)
+{
+    float3 a = x * (2.51f * x + 0.03f);
+    float3 b = x * (2.43f * x + 0.59f) + 0.14f;
+    return clamp(a / b, 0.0f, 1.0f);
+}
+
+// 6th order polynomial approximation of the AgX base contrast curve
+float3 agx_contrast(float3 x)
+{
+    float3 x2 = x * x;
+    float3 x4 = x2 * x2;
+    return 15.5f * x4 * x2 - 40.14f * x4 * x + 31.96f * x4 - 6.868f * x2 * x + 0.4298f * x2 + 0.1191f * x - 0.00232f;
+}
+
+// AgX like tone mapping, based on the minimal implementation by Benjamin Wrensch
+// returns linear color
+float3 agx(float3 color)
+{
+    const float min_ev = -12.47393f;
+    const float max_ev = 4.026069f;
+
+    // inset to the AgX working space
+    float3 v = (float3)(0.842479062253094f, 0.0423282422610123f, 0.0423756549057051f) * color.x +
+               (float3)(0.0784335999999992f, 0.878468636469772f, 0.0784336f) * color.y +
+               (float3)(0.0792237451477643f, 0.0791661274605434f, 0.879142973793104f) * color.z;
+
+    // log2 encoding
+    v = clamp(log2(max(v, (float3)1e-10f)), min_ev, max_ev);
+    v = (v - min_ev) / (max_ev - min_ev);
+    v = agx_contrast(v);
+
+    // outset back and decode to linear
+    v = (float3)(1.19687900512017f, -0.0528968517574562f, -0.0529716355144438f) * v.x +
+        (float3)(-0.0980208811401368f, 1.15190312990417f, -0.0980434501171241f) * v.y +
+        (float3)(-0.0990297440797205f, -0.0989611768448433f, 1.15107367264116f) * v.z;
+    return pow(max(v, (float3)0.0f), (float3)2.2f);
+}
+
+// sRGB opto-electronic transfer function
+float3 srgb_encode(float3 color)
+{
+    color = clamp(color, 0.0f, 1.0f);
+    float3 low = color * 12.92f;
+    float3 high = 1.055f * pow(color, (float3)(1.0f / 2.4f)) - 0.055f;
+    return select(high, low, color <= (float3)0.0031308f);
+}
+
+// convert an sRGB encoded color back to linear
+float3 srgb_decode(float3 color)
+{
+    float3 low = color / 12.92f;
+    float3 high = pow((color + 0.055f) / 1.055f, (float3)2.4f);
+    return select(high, low, color <= (float3)0.04045f);
+}
+
+// transform hdr radiance to an sRGB encoded display color, exposure is in EV
+float3 display_transform(float3 color, uint tone_mapping, float exposure)
+{
+    color = max(color, (float3)0.0f) * exp2(exposure);
+
+    switch (tone_mapping)
+    {
+        case TONE_MAPPING_REINHARD:
+            color = color / (1.0f + color);
+            break;
+        case TONE_MAPPING_ACES:
+            color = aces_filmic(color);
+            break;
+        case TONE_MAPPING_AGX:
+            color = agx(color);
+            break;
+        default:
+            break;
+    }
+
+    return srgb_encode(color);
+}
@@ -0,0 +1,48 @@
+#pragma once
+#include "src/kernels/tools/constants.cl"
+
+// filter.cl
+// Contains the pixel reconstruction filters, samples are weighted by their offset to the pixel center
+
+// reconstruction filters, keep in sync with PixelFilter in renderer.rs
+#define PIXEL_FILTER_BOX 0
+#define PIXEL_FILTER_TENT 1
+#define PIXEL_FILTER_BLACKMAN_HARRIS 2
+
+// radius of the filter footprint in pixels
+float filter_radius(uint pixel_filter)
+{
+    switch (pixel_filter)
+    {
+        case PIXEL_FILTER_TENT:
+            return 1.0f;
+        case PIXEL_FILTER_BLACKMAN_HARRIS:
+            return 1.5f;
+        default:
+            return 0.5f;
+    }
+}
+
+// one dimensional filter weight of an offset inside the filter radius
+float filter_weight_1d(uint pixel_filter, float offset, float radius)
+{
+    switch (pixel_filter)
+    {
+        case PIXEL_FILTER_TENT:
+            return max(0.0f, 1.0f - fabs(offset) / radius);
+        case PIXEL_FILTER_BLACKMAN_HARRIS:
+        {
+            float t = offset / (2.0f * radius) + 0.5f;
+            return 0.35875f - 0.48829f * cos(2.0f * PI * t) + 0.14128f * cos(4.0f * PI * t) - 0.01168f * cos(6.0f * PI * t);
+        }
+        default:
+            return 1.0f;
+    }
+}
+
+// separable filter weight of an offset from the pixel center
+float filter_weight(uint pixel_filter, float2 offset)
+{
+    float radius = filter_radius(pixel_filter);
+    return filter_weight_1d(pixel_filter, offset.x, radius) * filter_weight_1d(pixel_filter, offset.y, radius);
+}
@@ -0,0 +1,215 @@
+#pragma once
+#include "src/kernels/tools/constants.cl"
+#include "src/kernels/tools/random.cl"
+
+// microfacet.cl
+// Contains the GGX/Trowbridge-Reitz microfacet bsdf, layered over a lambertian base
+// directions are evaluated in a local frame where the shading normal is the z axis,
+// wo points away from the surface towards the viewer and wi towards the light
+
+// the alpha of very smooth surfaces is clamped, perfect mirrors are handled by reflectiveness
+#define MIN_GGX_ALPHA 0.001f
+
+// build an orthonormal basis around the normal
+void microfacet_basis(float3* normal, float3* tangent, float3* bitangent)
+{
+    float3 a = fabs(normal->x) > 0.9f ? (float3)(0, 1, 0) : (float3)(1, 0, 0);
+    *tangent = normalize(cross(a, *normal));
+    *bitangent = cross(*normal, *tangent);
+}
+
+float3 to_local(float3 v, float3* tangent, float3* bitangent, float3* normal)
+{
+    return (float3)(dot(v, *tangent), dot(v, *bitangent), dot(v, *normal));
+}
+
+float3 to_world(float3 v, float3* tangent, float3* bitangent, float3* normal)
+{
+    return *tangent * v.x + *bitangent * v.y + *normal * v.z;
+}
+
+float ggx_alpha(float roughness)
+{
+    return max(roughness * roughness, MIN_GGX_ALPHA);
+}
+
+// distribution of microfacet normals
+float ggx_d(float3 h, float alpha)
+{
+    float alpha2 = alpha * alpha;
+    float d = h.z * h.z * (alpha2 - 1.0f) + 1.0f;
+    return alpha2 / (PI * d * d);
+}
+
+// smith lambda, the fraction of microfacets hidden from a direction
+float ggx_lambda(float3 v, float alpha)
+{
+    float cos2 = v.z * v.z;
+    float tan2 = max(0.0f, 1.0f - cos2) / max(cos2, EPSILON);
+    return (-1.0f + sqrt(1.0f + alpha * alpha * tan2)) * 0.5f;
+}
+
+float ggx_g1(float3 v, float alpha)
+{
+    return 1.0f / (1.0f + ggx_lambda(v, alpha));
+}
+
+// height correlated masking and shadowing
+float ggx_g2(float3 wo, float3 wi, float alpha)
+{
+    return 1.0f / (1.0f + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha));
+}
+
+// sample a microfacet normal from the distribution of normals visible from wo
+// from: Sampling the GGX Distribution of Visible Normals, Heitz 2018
+float3 ggx_sample_vndf(float3 wo, float alpha, float r0, float r1)
+{
+    // stretch the view direction to the hemisphere configuration
+    float3 v = normalize((float3)(alpha * wo.x, alpha * wo.y, wo.z));
+
+    float length_squared = v.x * v.x + v.y * v.y;
+    float3 t1 = length_squared > 0.0f ? (float3)(-v.y, v.x, 0.0f) * rsqrt(length_squared) : (float3)(1.0f, 0.0f, 0.0f);
+    float3 t2 = cross(v, t1);
+
+    // sample the projected area of the visible hemisphere
+    float r = sqrt(r0);
+    float phi = 2.0f * PI * r1;
+    float p1 = r * cos(phi);
+    float p2 = r * sin(phi);
+    float s = 0.5f * (1.0f + v.z);
+    p2 = (1.0f - s) * sqrt(max(0.0f, 1.0f - p1 * p1)) + s * p2;
+
+    float3 n = t1 * p1 + t2 * p2 + v * sqrt(max(0.0f, 1.0f - p1 * p1 - p2 * p2));
+
+    // unstretch back to the ellipsoid configuration
+    return normalize((float3)(alpha * n.x, alpha * n.y, max(0.0f, n.z)));
+}
+
+// schlick fresnel, the grazing reflectance fades out for surfaces without specular reflectance
+float3 fresnel_schlick(float cos_theta, float3 f0)
+{
+    float f90 = clamp(50.0f * dot(f0, (float3)(0.2126f, 0.7152f, 0.0722f)), 0.0f, 1.0f);
+    float x = 1.0f - clamp(cos_theta, 0.0f, 1.0f);
+    return f0 + (f90 - f0) * (x * x * x * x * x);
+}
+
+// reflectance at normal incidence, dielectrics reflect a fraction of the light set by specular, metals tint it
+float3 microfacet_f0(float3* albedo, float metallic, float specular)
+{
+    return mix((float3)(0.08f * specular), *albedo, metallic);
+}
+
+// chance to sample the specular lobe instead of the diffuse lobe
+float microfacet_specular_probability(float3 wo, float3* albedo, float metallic, float3 f0)
+{
+    float3 luminance_weights = (float3)(0.2126f, 0.7152f, 0.0722f);
+    float specular_weight = dot(fresnel_schlick(wo.z, f0), luminance_weights);
+    float diffuse_weight = (1.0f - metallic) * (1.0f - dot(f0, luminance_weights)) * dot(*albedo, luminance_weights);
+    float total = specular_weight + diffuse_weight;
+    return total > 0.0f ? specular_weight / total : 0.0f;
+}
+
+// evaluate the bsdf and the pdf of sampling wi with microfacet_sample, both in the local frame
+float3 microfacet_evaluate_local(
+    float3 wo,
+    float3 wi,
+    float3* albedo,
+    float roughness,
+    float metallic,
+    float specular,
+    float* pdf
+)
+{
+    *pdf = 0.0f;
+    if (wo.z <= 0.0f || wi.z <= 0.0f)
+    {
+        return (float3)0;
+    }
+
+    float alpha = ggx_alpha(roughness);
+    float3 f0 = microfacet_f0(albedo, metallic, specular);
+    float specular_probability = microfacet_specular_probability(wo, albedo, metallic, f0);
+
+    float3 h = normalize(wo + wi);
+    float d = ggx_d(h, alpha);
+    float3 f = fresnel_schlick(dot(wo, h), f0);
+    float3 specular_bsdf = f * (d * ggx_g2(wo, wi, alpha) / (4.0f * wo.z * wi.z));
+
+    // the diffuse base only receives the light that is not reflected at normal incidence
+    float diffuse_scale = (1.0f - metallic) * (1.0f - dot(f0, (float3)(0.2126f, 0.7152f, 0.0722f)));
+    float3 diffuse_bsdf = *albedo * (diffuse_scale * INV_PI);
+
+    float specular_pdf = ggx_g1(wo, alpha) * d / (4.0f * wo.z);
+    float diffuse_pdf = wi.z * INV_PI;
+    *pdf = specular_probability * specular_pdf + (1.0f - specular_probability) * diffuse_pdf;
+
+    return specular_bsdf + diffuse_bsdf;
+}
+
+// evaluate the bsdf for a pair of world space directions, the pdf is over solid angle
+float3 microfacet_evaluate(
+    float3* normal,
+    float3* wo,
+    float3* wi,
+    float3* albedo,
+    float roughness,
+    float metallic,
+    float specular,
+    float* pdf
+)
+{
+    float3 tangent;
+    float3 bitangent;
+    microfacet_basis(normal, &tangent, &bitangent);
+    return microfacet_evaluate_local(
+        to_local(*wo, &tangent, &bitangent, normal),
+        to_local(*wi, &tangent, &bitangent, normal),
+        albedo,
+        roughness,
+        metallic,
+        specular,
+        pdf);
+}
+
+// sample a direction from either the specular or the diffuse lobe
+// returns the bsdf times the cosine over the pdf of the whole mixture, zero when the sample is below the surface
+float3 microfacet_sample(
+    float3* normal,
+    float3* wo,
+    float3* albedo,
+    float roughness,
+    float metallic,
+    float specular,
+    uint* seed,
+    float3* wi,
+    float* pdf
+)
+{
+    float3 tangent;
+    float3 bitangent;
+    microfacet_basis(normal, &tangent, &bitangent);
+
+    float3 local_wo = to_local(*wo, &tangent, &bitangent, normal);
+    float3 f0 = microfacet_f0(albedo, metallic, specular);
+    float specular_probability = microfacet_specular_probability(local_wo, albedo, metallic, f0);
+
+    float3 local_wi;
+    if (random_float(seed) < specular_probability)
+    {
+        float3 h = ggx_sample_vndf(local_wo, ggx_alpha(roughness), random_float(seed), random_float(seed));
+        local_wi = 2.0f * dot(local_wo, h) * h - local_wo;
+    }
+    else
+    {
+        float3 up = (float3)(0, 0, 1);
+        local_wi = random_cosine_hemisphere_direction(&up, seed);
+    }
+
+    *wi = to_world(local_wi, &tangent, &bitangent, normal);
+    float3 bsdf = microfacet_evaluate_local(local_wo, local_wi, albedo, roughness, metallic, specular, pdf);
+    if (*pdf <= 0.0f)
+    {
+        return (float3)0;
+    }
+    return bsdf * (local_wi.z / *pdf);
+}
@@ -0,0 +1,46 @@
+#pragma once
+
+// mis.cl
+// Contains the weights that combine light sampling and bsdf sampling with multiple importance sampling
+// every light is found by both strategies, the weights of the two samples of the same light add up to one
+
+// sampling strategies, keep in sync with SamplingStrategy in renderer.rs
+// the light and bsdf strategies only show the light found by that strategy and are meant for debugging
+#define SAMPLING_STRATEGY_MIS 0
+#define SAMPLING_STRATEGY_LIGHT 1
+#define SAMPLING_STRATEGY_BSDF 2
+
+float power_heuristic(float pdf, float other_pdf)
+{
+    float pdf2 = pdf * pdf;
+    float other_pdf2 = other_pdf * other_pdf;
+    return pdf2 + other_pdf2 > 0.0f ? pdf2 / (pdf2 + other_pdf2) : 0.0f;
+}
+
+// weight of a shadow ray, delta lights can not be found by bsdf sampling so they are never shared
+float light_sample_weight(uint sampling_strategy, float light_pdf, float bsdf_pdf, bool delta_light)
+{
+    switch (sampling_strategy)
+    {
+        case SAMPLING_STRATEGY_LIGHT:
+            return 1.0f;
+        case SAMPLING_STRATEGY_BSDF:
+            return 0.0f;
+        default:
+            return delta_light ? 1.0f : power_heuristic(light_pdf, bsdf_pdf);
+    }
+}
+
+// weight of a light that a bsdf sampled ray runs into
+float bsdf_sample_weight(uint sampling_strategy, float bsdf_pdf, float light_pdf)
+{
+    switch (sampling_strategy)
+    {
+        case SAMPLING_STRATEGY_LIGHT:
+            return 0.0f;
+        case SAMPLING_STRATEGY_BSDF:
+            return 1.0f;
+        default:
+            return power_heuristic(bsdf_pdf, light_pdf);
+    }
+}
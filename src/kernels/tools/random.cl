@@ -53,6 +53,65 @@ float3 random_hemisphere_direction(float3* normal, uint* seed)
     return dir * sign(dot(*normal, dir));
 }
 
+// cosine weighted direction around the normal, pdf is cos(theta) / PI
+float3 random_cosine_hemisphere_direction(float3* normal, uint* seed)
+{
+    float r0 = random_float(seed);
+    float r1 = random_float(seed);
+    float r = sqrt(r0);
+    float theta = 2.0f * PI * r1;
+
+    // build an orthonormal basis around the normal
+    float3 w = *normal;
+    float3 a = fabs(w.x) > 0.9f ? (float3)(0, 1, 0) : (float3)(1, 0, 0);
+    float3 u = normalize(cross(a, w));
+    float3 v = cross(w, u);
+
+    return normalize(u * (r * cos(theta)) + v * (r * sin(theta)) + w * sqrt(1.0f - r0));
+}
+
+// pick an index from a normalized cumulative distribution, the first entry that is larger than r
+uint sample_cdf(float r, float* cdf, uint count)
+{
+    uint low = 0;
+    uint high = count - 1;
+    while (low < high)
+    {
+        uint mid = (low + high) / 2;
+        if (cdf[mid] <= r)
+        {
+            low = mid + 1;
+        }
+        else
+        {
+            high = mid;
+        }
+    }
+    return low;
+}
+
+// probability of an index that was picked from a normalized cumulative distribution
+float cdf_probability(float* cdf, uint idx)
+{
+    return cdf[idx] - (idx > 0 ? cdf[idx - 1] : 0.0f);
+}
+
+// uniform direction inside a cone around an axis, the pdf over solid angle is 1 / (2 * PI * (1 - cos_max))
+float3 random_cone_direction(float3* axis, float cos_max, uint* seed)
+{
+    float cos_theta = 1.0f - random_float(seed) * (1.0f - cos_max);
+    float sin_theta = sqrt(max(0.0f, 1.0f - cos_theta * cos_theta));
+    float phi = 2.0f * PI * random_float(seed);
+
+    // build an orthonormal basis around the axis
+    float3 w = *axis;
+    float3 a = fabs(w.x) > 0.9f ? (float3)(0, 1, 0) : (float3)(1, 0, 0);
+    float3 u = normalize(cross(a, w));
+    float3 v = cross(w, u);
+
+    return normalize(u * (sin_theta * cos(phi)) + v * (sin_theta * sin(phi)) + w * cos_theta);
+}
+
 const float2 c_blue_noise_in_disk[64] = {
     (float2)(0.478712,0.875764),
     (float2)(-0.337956,-0.793959),
@@ -123,24 +182,26 @@ const float2 c_blue_noise_in_disk[64] = {
 #define BLUE_NOISE_TEXTURE_WIDTH_HEIGHT (64)
 #define BLUE_NOISE_TEXTURE_MAX (BLUE_NOISE_TEXTURE_WIDTH_HEIGHT * BLUE_NOISE_TEXTURE_WIDTH_HEIGHT)
 #define BLUE_NOISE_TEXTURE_SAMPLE_MASK (BLUE_NOISE_TEXTURE_MAX - 1)
+#define BLUE_NOISE_TEXTURE_COORDINATE_MASK (BLUE_NOISE_TEXTURE_WIDTH_HEIGHT - 1)
+#define BLUE_NOISE_DISK_SAMPLES (64)
 
 // from: https://www.shadertoy.com/view/3sfBWs
 // ONLY WORKS FOR ONE SHADOW RAY PER RAY
 // MULTIPLE SHOULD DO THE THETA CALCULATIONS OUTSIDE
 float2 random_blue_noise_point(__constant uchar* blue_noise_texture, uint frame_idx, uint pixel_x, uint pixel_y)
 {
-    uint sample_idx = (pixel_y & BLUE_NOISE_TEXTURE_SAMPLE_MASK) * BLUE_NOISE_TEXTURE_WIDTH_HEIGHT + (pixel_x & BLUE_NOISE_TEXTURE_SAMPLE_MASK);
+    uint sample_idx = (pixel_y & BLUE_NOISE_TEXTURE_COORDINATE_MASK) * BLUE_NOISE_TEXTURE_WIDTH_HEIGHT + (pixel_x & BLUE_NOISE_TEXTURE_COORDINATE_MASK);
     float blue_noise = ((float)blue_noise_texture[sample_idx] / 255.0f) + GOLDEN_RATIO * (float)frame_idx;
     blue_noise -= floor(blue_noise);
     float theta = blue_noise * 2.0f * PI;
     float cos_theta = cos(theta);
     float sin_theta = sin(theta);
 
-    float2 sample_position = c_blue_noise_in_disk[frame_idx & BLUE_NOISE_TEXTURE_SAMPLE_MASK];
+    float2 sample_position = c_blue_noise_in_disk[frame_idx % BLUE_NOISE_DISK_SAMPLES];
 
     float2 disk_point = (float2)(
         sample_position.x * cos_theta - sample_position.y * sin_theta,
-        sample_position.y * sin_theta + sample_position.y * cos_theta
+        sample_position.x * sin_theta + sample_position.y * cos_theta
     );
 
     return (disk_point + (float2)1) / 2;
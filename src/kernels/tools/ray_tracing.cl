@@ -3,6 +3,10 @@
 // ray_tracing.cl
 // Contains code related to ray_tracing, such as reflect and refract
 
+// state that travels with a ray along its path
+#define RAY_FLAG_INSIDE 1
+#define RAY_FLAG_SPECULAR 2
+
 // reflect a ray
 float3 reflect(float3 I, float3 N)
 {
@@ -39,4 +43,4 @@ float fresnel_reflect_amount(float n1, float n2, float3 N, float3 I, float refle
     // adjust reflect multiplier for object reflectivity
     ret = (reflectivity + (1.0 - reflectivity) * ret);
     return clamp(ret, 0.0, 1.0);
-}
\ No newline at end of file
+}
@@ -38,6 +38,16 @@ float3 transform_vector(float3* a, struct mat4* m)
    );
 }
 
+// transform a normal with the inverse of a matrix, multiplies with the transposed inverse
+float3 transform_normal(float3* a, struct mat4* inv_m)
+{
+    return (float3)(
+       inv_m->cell[0] * a->x + inv_m->cell[4] * a->y + inv_m->cell[8] * a->z,
+       inv_m->cell[1] * a->x + inv_m->cell[5] * a->y + inv_m->cell[9] * a->z,
+       inv_m->cell[2] * a->x + inv_m->cell[6] * a->y + inv_m->cell[10] * a->z
+   );
+}
+
 struct mat4 invert_mat4(struct mat4* other)
 {
     float inv[16] = {
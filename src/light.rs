@@ -0,0 +1,80 @@
+use crate::math::*;
+
+// the light structs are uploaded as they are, keep in sync with the structs in lights.cl
+
+// light that shines equally in all directions from a single point
+// the intensity falls off with the squared distance and is faded out towards the range, a range of 0 is unlimited
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PointLight
+{
+    pub position: Float3,
+    pub intensity: Float3,
+    pub range: f32
+}
+
+impl PointLight
+{
+    pub fn new(position: Float3, color: Float3, intensity: f32, range: f32) -> Self
+    {
+        PointLight{
+            position,
+            intensity: color * intensity,
+            range
+        }
+    }
+}
+
+// point light that is limited to a cone, the light fades out between the inner and outer cone angle
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SpotLight
+{
+    pub position: Float3,
+    pub direction: Float3,
+    pub intensity: Float3,
+    pub range: f32,
+    pub cos_inner_angle: f32,
+    pub cos_outer_angle: f32
+}
+
+impl SpotLight
+{
+    // the cone angles are half angles in radians, measured from the direction
+    pub fn new(position: Float3, direction: Float3, color: Float3, intensity: f32, range: f32, inner_angle: f32, outer_angle: f32) -> Self
+    {
+        let outer_angle = outer_angle.max(inner_angle);
+        SpotLight{
+            position,
+            direction: normalize(&direction),
+            intensity: color * intensity,
+            range,
+            cos_inner_angle: inner_angle.cos(),
+            cos_outer_angle: outer_angle.cos()
+        }
+    }
+}
+
+// light that arrives from a distant disk, like the sun
+// the irradiance is spread over the angular diameter of the disk, which softens the shadows
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DirectionalLight
+{
+    pub direction: Float3,
+    pub irradiance: Float3,
+    pub cos_half_angle: f32
+}
+
+impl DirectionalLight
+{
+    // the direction is the direction the light travels in, the angular diameter is in radians
+    pub fn new(direction: Float3, color: Float3, irradiance: f32, angular_diameter: f32) -> Self
+    {
+        DirectionalLight{
+            direction: normalize(&direction),
+            irradiance: color * irradiance,
+            cos_half_angle: (angular_diameter * 0.5).cos()
+        }
+    }
+}
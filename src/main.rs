@@ -16,7 +16,13 @@ mod renderer;
 mod scene;
 mod render_components;
 mod material;
+mod light;
+mod environment;
+mod texture;
+mod medium;
 mod bvh_construction;
+mod bvh_cache;
+mod tlas;
 
 use surface::*;
 use crate::opengl::{draw_quad, GLTexture, Shader, TextureType};
@@ -44,10 +50,12 @@ fn main() {
 
     let shader: Shader = Shader::compile(
         CString::new("#version 330\nin vec4 p;\nin vec2 t;out vec2 u;void main(){u=t;gl_Position=p;}").unwrap(),
-        CString::new("#version 330\nuniform sampler2D c;in vec2 u;out vec4 f;void main(){f=/*sqrt*/(texture(c,u));}").unwrap()
+        CString::new("#version 330\nuniform sampler2D c;in vec2 u;out vec4 f;void main(){f=texture(c,u);}").unwrap()
     );
     let mut render_target: GLTexture = GLTexture::new(SCRWIDTH as u32, SCRHEIGHT as u32, TextureType::INTTARGET);
-    let mut application: Application = Application::new();
+    // the optional first argument is an .hdr or .exr environment map that lights the scene
+    let environment_path = std::env::args().nth(1).map(std::path::PathBuf::from);
+    let mut application: Application = Application::new(environment_path.as_deref());
 
     let mut imgui = imgui::Context::create();
     let mut imgui_glfw = ImguiGLFW::new(&mut imgui, &mut window);
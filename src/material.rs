@@ -9,9 +9,68 @@ pub fn convert_color_to_u32(color: &Float3) -> u32
     return (r << 16) + (g << 8) + b;
 }
 
+// relative luminance of a linear color
+pub fn luminance(color: &Float3) -> f32
+{
+    return 0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z;
+}
+
 pub struct Material
 {
     pub colors: Vec<Float3>,
     pub reflectiveness: Vec<f32>,
-    pub refractive_indices: Vec<f32>
+    pub refractive_indices: Vec<f32>,
+    pub emission: Float3,
+    pub emission_strength: f32,
+
+    // microfacet parameters, a specular of 0 and metallic of 0 is a purely diffuse surface
+    pub roughness: f32,
+    pub metallic: f32,
+    pub specular: f32,
+
+    // indices into the textures of the scene
+    pub albedo_texture: Option<u32>,
+    pub normal_texture: Option<u32>,
+
+    // index into the media of the scene that fills closed meshes with this material,
+    // without a refractive index the surface itself is invisible and only bounds the medium
+    pub medium: Option<u32>
+}
+
+impl Material
+{
+    // opaque diffuse material without textures
+    pub fn from_color(color: Float3) -> Self
+    {
+        Material{
+            colors: vec![color],
+            reflectiveness: vec![0.0],
+            refractive_indices: vec![0.0],
+            emission: Float3::zero(),
+            emission_strength: 0.0,
+            roughness: 0.5,
+            metallic: 0.0,
+            specular: 0.0,
+            albedo_texture: None,
+            normal_texture: None,
+            medium: None
+        }
+    }
+
+    // radiance that leaves every point of a surface with this material
+    pub fn emitted_radiance(&self) -> Float3
+    {
+        return self.emission * self.emission_strength;
+    }
+
+    pub fn is_emissive(&self) -> bool
+    {
+        return luminance(&self.emitted_radiance()) > 0.0;
+    }
+
+    // surfaces that only bound a medium let rays and shadow rays through unchanged
+    pub fn is_medium_boundary(&self) -> bool
+    {
+        return self.medium.is_some() && self.refractive_indices.iter().all(|index| *index <= 0.0);
+    }
 }
\ No newline at end of file
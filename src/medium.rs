@@ -0,0 +1,42 @@
+use crate::math::*;
+use crate::render_components::AABB;
+
+// homogeneous participating medium, the coefficients are per unit of distance
+// uploaded as it is, keep in sync with the medium struct in medium.cl
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Medium
+{
+    pub absorption: Float3,
+    pub scattering: Float3,
+
+    // henyey-greenstein anisotropy, negative values scatter backwards, positive values forwards
+    pub anisotropy: f32
+}
+
+impl Medium
+{
+    pub fn new(absorption: Float3, scattering: Float3, anisotropy: f32) -> Self
+    {
+        Medium{
+            absorption,
+            scattering,
+            anisotropy: anisotropy.clamp(-0.99, 0.99)
+        }
+    }
+
+    // medium from the color it scatters and its density, the rest of the light is absorbed
+    pub fn from_albedo(albedo: Float3, density: f32, anisotropy: f32) -> Self
+    {
+        let scattering = albedo * density;
+        let absorption = (Float3::from_a(1.0) - albedo) * density;
+        return Medium::new(absorption, scattering, anisotropy);
+    }
+}
+
+// medium that fills the bounds everywhere outside of the closed meshes that have a medium of their own
+pub struct Fog
+{
+    pub medium: Medium,
+    pub bounds: AABB
+}
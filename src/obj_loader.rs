@@ -1,4 +1,7 @@
-use crate::material::Material;
+use std::collections::HashMap;
+use log::warn;
+use crate::material::{Material, luminance};
+use crate::texture::{Texture, WrapMode};
 use crate::math::*;
 use crate::render_components::*;
 
@@ -46,59 +49,331 @@ fn compute_vertex_normals(triangle_normals: &Vec<Float3>, triangle_vertex_ids: &
     return vertex_normals;
 }
 
-pub fn load_obj(path: &std::path::Path) -> (Vec<Mesh>, Vec<Material>)
+// per vertex tangents in the MikkTSpace convention, the w component holds the handedness so that
+// bitangent = w * cross(normal, tangent), the triangle tangents are weighted by the angle at the vertex
+fn compute_vertex_tangents(triangles: &Vec<Triangle>, triangle_vertex_ids: &Vec<Uint3>, vertex_normals: &Vec<Float3>, vertex_uvs: &Vec<Float2>) -> Vec<Float4>
+{
+    let num_vertices = vertex_normals.len();
+    let mut tangents: Vec<Float3> = vec![Float3::zero(); num_vertices];
+    let mut bitangents: Vec<Float3> = vec![Float3::zero(); num_vertices];
+
+    for (triangle, ids) in triangles.iter().zip(triangle_vertex_ids)
+    {
+        let positions = [triangle.vertex0, triangle.vertex1, triangle.vertex2];
+        let ids = [ids.x as usize, ids.y as usize, ids.z as usize];
+        let uvs = [vertex_uvs[ids[0]], vertex_uvs[ids[1]], vertex_uvs[ids[2]]];
+
+        let edge1 = positions[1] - positions[0];
+        let edge2 = positions[2] - positions[0];
+        let delta_uv1 = uvs[1] - uvs[0];
+        let delta_uv2 = uvs[2] - uvs[0];
+
+        let determinant = delta_uv1.x * delta_uv2.y - delta_uv2.x * delta_uv1.y;
+        if determinant.abs() < 1e-12
+        {
+            continue;
+        }
+
+        let tangent = (edge1 * delta_uv2.y - edge2 * delta_uv1.y) * (1.0 / determinant);
+        let bitangent = (edge2 * delta_uv1.x - edge1 * delta_uv2.x) * (1.0 / determinant);
+
+        for corner in 0..3
+        {
+            let to_next = normalize(&(positions[(corner + 1) % 3] - positions[corner]));
+            let to_previous = normalize(&(positions[(corner + 2) % 3] - positions[corner]));
+            let angle = dot(&to_next, &to_previous).clamp(-1.0, 1.0).acos();
+
+            tangents[ids[corner]] += tangent * angle;
+            bitangents[ids[corner]] += bitangent * angle;
+        }
+    }
+
+    let mut vertex_tangents: Vec<Float4> = Vec::with_capacity(num_vertices);
+    for i in 0..num_vertices
+    {
+        let normal = vertex_normals[i];
+
+        // gram-schmidt orthogonalize against the normal, vertices without uv derivatives get any perpendicular tangent
+        let mut tangent = tangents[i] - normal * dot(&normal, &tangents[i]);
+        if dot(&tangent, &tangent) < 1e-12
+        {
+            let axis = if normal.x.abs() > 0.9 { Float3::from_xyz(0.0, 1.0, 0.0) } else { Float3::from_xyz(1.0, 0.0, 0.0) };
+            tangent = cross(&axis, &normal);
+        }
+        let tangent = normalize(&tangent);
+
+        let handedness = if dot(&cross(&normal, &tangent), &bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        vertex_tangents.push(Float4::from_xyzw(tangent.x, tangent.y, tangent.z, handedness));
+    }
+
+    return vertex_tangents;
+}
+
+// split a texture statement like "-clamp on -s 2 2 1 wood.png" into the file and its wrap mode,
+// -clamp is the only option that is used, the others are skipped with their values
+fn parse_texture_statement(statement: &str) -> (String, WrapMode)
+{
+    let mut wrap_mode = WrapMode::Repeat;
+    let tokens: Vec<&str> = statement.split_whitespace().collect();
+    let mut i = 0;
+    while i < tokens.len() && tokens[i].starts_with('-')
+    {
+        let option = tokens[i];
+        i += 1;
+        match option
+        {
+            "-clamp" =>
+            {
+                if tokens.get(i) == Some(&"on")
+                {
+                    wrap_mode = WrapMode::Clamp;
+                }
+                i += 1;
+            }
+            "-mm" => i += 2,
+            // offsets and scales take one to three values
+            "-o" | "-s" | "-t" =>
+            {
+                let mut values = 0;
+                while values < 3 && i + 1 < tokens.len() && tokens[i].parse::<f32>().is_ok()
+                {
+                    i += 1;
+                    values += 1;
+                }
+            }
+            _ => i += 1
+        }
+    }
+
+    // file names may contain spaces
+    return (tokens[i.min(tokens.len())..].join(" "), wrap_mode);
+}
+
+// load a texture once, materials that share a texture statement share the texture
+fn load_texture(directory: &std::path::Path, statement: &Option<String>, texture_ids: &mut HashMap<String, u32>, textures: &mut Vec<Texture>) -> Option<u32>
+{
+    let statement = statement.as_ref()?;
+    if let Some(idx) = texture_ids.get(statement)
+    {
+        return Some(*idx);
+    }
+
+    let (file, wrap_mode) = parse_texture_statement(statement);
+    let path = directory.join(&file);
+    if file.is_empty() || !path.exists()
+    {
+        warn!("texture {} not found", path.display());
+        return None;
+    }
+
+    let idx = textures.len() as u32;
+    textures.push(Texture::from_file(&path, wrap_mode));
+    texture_ids.insert(statement.clone(), idx);
+    return Some(idx);
+}
+
+fn parse_color(value: &str) -> Option<Float3>
+{
+    let values: Vec<f32> = value.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+    return match values.len()
+    {
+        0 => None,
+        1 | 2 => Some(Float3::from_a(values[0])),
+        _ => Some(Float3::from_xyz(values[0], values[1], values[2]))
+    };
+}
+
+// translate the mtl parameters to the material model of the renderer
+fn convert_material(mtl: &tobj::Material, directory: &std::path::Path, texture_ids: &mut HashMap<String, u32>, textures: &mut Vec<Texture>) -> Material
+{
+    let to_float3 = |c: [f32; 3]| Float3::from_xyz(c[0], c[1], c[2]);
+
+    let mut material = Material::from_color(mtl.diffuse.map(to_float3).unwrap_or(Float3::from_a(0.8)));
+    let illum = mtl.illumination_model.unwrap_or(2);
+
+    // illumination models 0 and 1 have no specular highlight
+    let specular_color = mtl.specular.map(to_float3).unwrap_or(Float3::zero());
+    if illum >= 2
+    {
+        // a dielectric reflectance of 0.08 is the largest specular value
+        material.specular = (luminance(&specular_color) / 0.08).clamp(0.0, 1.0);
+    }
+
+    // blinn-phong exponent to ggx roughness, alpha = sqrt(2 / (Ns + 2)) and alpha = roughness^2
+    if let Some(shininess) = mtl.shininess
+    {
+        material.roughness = (2.0 / (shininess.max(0.0) + 2.0)).sqrt().sqrt();
+    }
+
+    // models 3 and 5 are ray traced mirrors, 4, 6, 7 and 9 are glass
+    match illum
+    {
+        3 | 5 => material.reflectiveness = vec![luminance(&specular_color).clamp(0.0, 1.0)],
+        4 | 6 | 7 | 9 => material.refractive_indices = vec![mtl.optical_density.unwrap_or(1.5).max(1.0)],
+        _ => {}
+    }
+
+    // dissolved materials are treated as glass
+    if mtl.dissolve.unwrap_or(1.0) < 1.0
+    {
+        material.refractive_indices = vec![mtl.optical_density.unwrap_or(1.5).max(1.0)];
+    }
+
+    // emission and the pbr extension are not parsed by tobj
+    if let Some(emission) = mtl.unknown_param.get("Ke").and_then(|v| parse_color(v))
+    {
+        material.emission = emission;
+        material.emission_strength = if luminance(&emission) > 0.0 { 1.0 } else { 0.0 };
+    }
+    if let Some(roughness) = mtl.unknown_param.get("Pr").and_then(|v| v.trim().parse::<f32>().ok())
+    {
+        material.roughness = roughness;
+    }
+    if let Some(metallic) = mtl.unknown_param.get("Pm").and_then(|v| v.trim().parse::<f32>().ok())
+    {
+        material.metallic = metallic;
+    }
+
+    material.albedo_texture = load_texture(directory, &mtl.diffuse_texture, texture_ids, textures);
+    material.normal_texture = load_texture(directory, &mtl.normal_texture, texture_ids, textures);
+
+    return material;
+}
+
+// load the meshes of an obj file together with their materials and the textures the materials use,
+// the material index of a mesh points into the returned materials
+pub fn load_obj(path: &std::path::Path) -> (Vec<Mesh>, Vec<Material>, Vec<Texture>)
 {
     let mut options = tobj::LoadOptions::default();
     options.triangulate = true;
 
-    let (models, _) = tobj::load_obj(path, &options).expect("Failed to load obj file");
+    // texture coordinates need their own vertices at uv seams
+    options.single_index = true;
 
-    let mut meshes: Vec<Mesh> = Vec::new();
-    let mats: Vec<Material> = Vec::new();
+    let (models, mtl_materials) = tobj::load_obj(path, &options).expect("Failed to load obj file");
 
-    for m in &models
-    {
-        let mesh = &m.mesh;
-        let mat_id = mesh.material_id;
+    let mtl_materials = mtl_materials.unwrap_or_else(|_| {
+        warn!("no materials found for {}", path.display());
+        Vec::new()
+    });
 
-        let mut vertices: Vec<Float3> = Vec::with_capacity(mesh.positions.len() / 3);
-        let mut triangles: Vec<Triangle> = Vec::with_capacity(mesh.indices.len() / 3);
-        let mut triangle_vertex_ids: Vec<Uint3> = Vec::with_capacity(mesh.indices.len() / 3);
+    let directory = path.parent().unwrap_or(std::path::Path::new("."));
+    let mut texture_ids: HashMap<String, u32> = HashMap::new();
+    let mut textures: Vec<Texture> = Vec::new();
+    let mats: Vec<Material> = mtl_materials.iter()
+        .map(|mtl| convert_material(mtl, directory, &mut texture_ids, &mut textures))
+        .collect();
 
-        for vtx in 0..mesh.positions.len() / 3
+    // tobj splits an object into a model per usemtl group, merge them back into one mesh per object
+    // and remember the material of every triangle
+    let mut groups: Vec<(&str, Vec<&tobj::Model>)> = Vec::new();
+    for m in &models
+    {
+        match groups.iter_mut().find(|(name, _)| *name == m.name.as_str())
         {
-            vertices.push(Float3::from_xyz(
-                mesh.positions[3 * vtx + 0],
-                mesh.positions[3 * vtx + 1],
-                mesh.positions[3 * vtx + 2]
-            ));
+            Some((_, group)) => group.push(m),
+            None => groups.push((m.name.as_str(), vec![m]))
         }
+    }
+
+    let mut meshes: Vec<Mesh> = Vec::new();
+
+    for (_, group) in &groups
+    {
+        let mut vertices: Vec<Float3> = Vec::new();
+        let mut file_normals: Vec<Float3> = Vec::new();
+        let mut file_uvs: Vec<Float2> = Vec::new();
+        let mut triangles: Vec<Triangle> = Vec::new();
+        let mut triangle_vertex_ids: Vec<Uint3> = Vec::new();
+        let mut triangle_material_ids: Vec<u32> = Vec::new();
+        let mut has_normals = true;
+        let mut has_uvs = true;
 
-        for vtx in 0..mesh.indices.len() / 3
+        for m in group
         {
-            let vertex_id = Uint3::from_xyz(mesh.indices[3 * vtx + 0], mesh.indices[3 * vtx + 1], mesh.indices[3 * vtx + 2]);
+            let mesh = &m.mesh;
+            let vertex_offset = vertices.len() as u32;
+            let num_vertices = mesh.positions.len() / 3;
+
+            // triangles without a valid material use the material of the object
+            let material_id = mesh.material_id.filter(|id| *id < mats.len()).map(|id| id as u32).unwrap_or(u32::MAX);
+
+            for vtx in 0..num_vertices
+            {
+                vertices.push(Float3::from_xyz(
+                    mesh.positions[3 * vtx + 0],
+                    mesh.positions[3 * vtx + 1],
+                    mesh.positions[3 * vtx + 2]
+                ));
+            }
+
+            has_normals &= mesh.normals.len() == mesh.positions.len();
+            if has_normals
+            {
+                for vtx in 0..num_vertices
+                {
+                    file_normals.push(normalize(&Float3::from_xyz(
+                        mesh.normals[3 * vtx + 0],
+                        mesh.normals[3 * vtx + 1],
+                        mesh.normals[3 * vtx + 2]
+                    )));
+                }
+            }
+
+            has_uvs &= mesh.texcoords.len() == num_vertices * 2;
+            if has_uvs
+            {
+                for vtx in 0..num_vertices
+                {
+                    file_uvs.push(Float2::from_xy(mesh.texcoords[2 * vtx + 0], mesh.texcoords[2 * vtx + 1]));
+                }
+            }
+
+            for vtx in 0..mesh.indices.len() / 3
+            {
+                let vertex_id = Uint3::from_xyz(
+                    mesh.indices[3 * vtx + 0] + vertex_offset,
+                    mesh.indices[3 * vtx + 1] + vertex_offset,
+                    mesh.indices[3 * vtx + 2] + vertex_offset
+                );
 
-            triangles.push(Triangle {
-                tri_idx: vtx as u32,
-                vertex0: vertices[vertex_id.x as usize],
-                vertex1: vertices[vertex_id.y as usize],
-                vertex2: vertices[vertex_id.z as usize],
-            });
+                triangles.push(Triangle {
+                    vertex0: vertices[vertex_id.x as usize],
+                    vertex1: vertices[vertex_id.y as usize],
+                    vertex2: vertices[vertex_id.z as usize],
+                    tri_idx: triangles.len() as u32,
+                });
 
-            triangle_vertex_ids.push(vertex_id);
+                triangle_vertex_ids.push(vertex_id);
+                triangle_material_ids.push(material_id);
+            }
         }
 
-        let triangle_normals = compute_triangle_normals(&triangles);
-        let vertex_normals = compute_vertex_normals(&triangle_normals, &triangle_vertex_ids, vertices.len());
+        // vertices are split at seams, so computed normals would show the seams, prefer the normals of the file
+        let vertex_normals = if has_normals
+        {
+            file_normals
+        }
+        else
+        {
+            let triangle_normals = compute_triangle_normals(&triangles);
+            compute_vertex_normals(&triangle_normals, &triangle_vertex_ids, vertices.len())
+        };
+
+        let vertex_uvs = if has_uvs { file_uvs } else { vec![Float2::zero(); vertices.len()] };
+
+        let vertex_tangents = compute_vertex_tangents(&triangles, &triangle_vertex_ids, &vertex_normals, &vertex_uvs);
 
         meshes.push(Mesh{
             triangles,
             triangle_vertex_ids,
             vertex_normals,
-            vertex_uvs: vec![]
+            vertex_uvs,
+            vertex_tangents,
+            triangle_material_ids
         });
     }
 
 
-    return (meshes, mats);
+    return (meshes, mats, textures);
 }
\ No newline at end of file
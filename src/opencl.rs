@@ -21,12 +21,13 @@ use cl3::platform::*;
 use cl3::types::*;
 use cl3::context::*;
 use cl3::ext::{CL_PROGRAM_BINARIES, CL_QUEUE_PROFILING_ENABLE};
-use cl3::kernel::{create_kernel, set_kernel_arg};
+use cl3::kernel::{CL_KERNEL_NUM_ARGS, create_kernel, get_kernel_info, set_kernel_arg};
 use cl3::memory::*;
 use cl3::program::{CL_PROGRAM_BUILD_LOG, create_program_with_source, get_program_build_info};
 use crate::math::*;
 use crate::opencl::OpenCLVendor::Nvidia;
 use crate::timer::Timer;
+use log::error;
 
 /// Finds all the OpenCL platforms and devices on a system.
 ///
@@ -513,6 +514,15 @@ impl OpenCLKernel
         }
     }
 
+    // the number of parameters of the kernel function
+    pub fn num_arguments(&self) -> u32
+    {
+        let num_arguments: cl_uint = get_kernel_info(self.kernel, CL_KERNEL_NUM_ARGS)
+            .expect("Failed to get kernel argument count")
+            .into();
+        return num_arguments;
+    }
+
     pub fn run(&self, cl: &OpenCL, count: usize)
     {
         unsafe
@@ -693,3 +703,16 @@ impl<T: Clone> OpenCLBuffer<T>
 
 }
 
+impl<T> Drop for OpenCLBuffer<T>
+{
+    fn drop(&mut self) {
+        unsafe
+            {
+                // panicking here would abort when the buffer is dropped while unwinding
+                if let Err(error) = release_mem_object(self.buffer)
+                {
+                    error!("Failed to drop buffer: {}", error);
+                }
+            }
+    }
+}
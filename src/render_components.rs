@@ -1,16 +1,18 @@
 use crate::math::*;
 use crate::bvh_construction::*;
 
+// uploaded as it is, keep in sync with the triangle struct in triangle.cl
+#[repr(C)]
 #[derive(Clone, Copy)]
 pub struct Triangle
 {
-    pub tri_idx: u32,
     pub vertex0: Float3,
     pub vertex1: Float3,
     pub vertex2: Float3,
+    pub tri_idx: u32,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct AABB
 {
     pub min_bound: Float3,
@@ -118,6 +120,20 @@ impl AABB
         self.max_bound = *max_bound;
     }
 
+    // bounds of the box after it is transformed, found from its eight corners
+    pub fn transformed(&self, transform: &Mat4) -> Self
+    {
+        let mut bounds = AABB::from_empty();
+        for corner in 0..8
+        {
+            let x = if corner & 1 == 0 { self.min_bound.x } else { self.max_bound.x };
+            let y = if corner & 2 == 0 { self.min_bound.y } else { self.max_bound.y };
+            let z = if corner & 4 == 0 { self.min_bound.z } else { self.max_bound.z };
+            bounds.grow(&transform_position(&Float3::from_xyz(x, y, z), transform));
+        }
+        return bounds;
+    }
+
     pub fn center(&self, axis: usize) -> f32
     {
         match axis
@@ -258,7 +274,7 @@ pub fn triangle_is_in_aabb(aabb: &AABB, triangle: &Triangle, triangle_normal: &F
     aabb.contains2(&triangle.vertex0) || aabb.contains2(&triangle.vertex1) || aabb.contains2(&triangle.vertex2) || intersect_aabb_triangle(aabb, triangle, triangle_normal)
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct BVHNode
 {
     pub bounds: AABB,
@@ -278,17 +294,20 @@ pub struct BVH
 {
     pub bvh_nodes: Vec<BVHNode>,
     pub triangle_idx: Vec<usize>,
+    pub stats: BVHStats
 }
 
 impl BVH
 {
-    pub fn from_mesh(triangles: &Vec<Triangle>, bounds: &AABB, bin_size: usize) -> Self
+    pub fn from_mesh(triangles: &Vec<Triangle>, bounds: &AABB, options: &BVHBuildOptions) -> Self
     {
-        let constructor = BVHConstructor::from_mesh(triangles, bounds, bin_size);
+        let constructor = BVHConstructor::from_mesh(triangles, bounds, options);
+        let stats = constructor.stats();
         BVH
         {
             bvh_nodes: constructor.bvh_nodes,
-            triangle_idx: constructor.triangle_idx
+            triangle_idx: constructor.triangle_idx,
+            stats
         }
     }
 }
@@ -312,5 +331,10 @@ pub struct Mesh
     pub triangles: Vec<Triangle>,
     pub triangle_vertex_ids: Vec<Uint3>,
     pub vertex_normals: Vec<Float3>,
-    pub vertex_uvs: Vec<Float2>
+    pub vertex_uvs: Vec<Float2>,
+    pub vertex_tangents: Vec<Float4>,
+
+    // material of every triangle, indexing the materials that were loaded with the mesh,
+    // u32::MAX uses the material of the object
+    pub triangle_material_ids: Vec<u32>
 }
\ No newline at end of file
@@ -2,12 +2,14 @@ use std::path;
 use log::info;
 use crate::math::{Float3, random_uint_s};
 use crate::opencl::*;
-use crate::renderer::RenderMode::Albedo;
+use crate::renderer::RenderMode::AccumulatedLight;
 use crate::surface::{SCRHEIGHT, SCRWIDTH};
 use image::GenericImageView;
 use crate::camera::Camera;
 use crate::scene::Scene;
 
+const NUM_PIXELS: usize = SCRWIDTH * SCRHEIGHT;
+
 #[derive(PartialEq, Copy, Clone)]
 pub enum RenderMode
 {
@@ -18,12 +20,64 @@ pub enum RenderMode
     LightLayer
 }
 
+// display transforms, keep in sync with the defines in color.cl
+#[derive(PartialEq, Copy, Clone)]
+pub enum ToneMapping
+{
+    Linear = 0,
+    Reinhard = 1,
+    Aces = 2,
+    AgX = 3
+}
+
+// pixel reconstruction filters, keep in sync with the defines in filter.cl
+#[derive(PartialEq, Copy, Clone)]
+pub enum PixelFilter
+{
+    Box = 0,
+    Tent = 1,
+    BlackmanHarris = 2
+}
+
+// how the lights that both next event estimation and bsdf sampling find are combined,
+// keep in sync with the defines in mis.cl
+#[derive(PartialEq, Copy, Clone)]
+pub enum SamplingStrategy
+{
+    MultipleImportance = 0,
+    Light = 1,
+    Bsdf = 2
+}
+
 #[derive(PartialEq, Copy, Clone)]
 pub struct RenderSettings
 {
     pub render_mode: RenderMode,
     pub num_primary_rays: usize,
     pub num_bounces: usize,
+    pub light_layer: usize,
+    pub tone_mapping: ToneMapping,
+    pub exposure: f32,
+    pub russian_roulette_start: usize,
+    pub russian_roulette_max_survival: f32,
+    pub pixel_filter: PixelFilter,
+    pub environment_rotation: f32,
+    pub environment_intensity: f32,
+    pub sampling_strategy: SamplingStrategy,
+}
+
+impl RenderSettings
+{
+    // the primary rays are spread evenly over the pixels
+    pub fn samples_per_pixel(&self) -> usize
+    {
+        return (self.num_primary_rays / NUM_PIXELS).max(1);
+    }
+
+    pub fn set_samples_per_pixel(&mut self, samples_per_pixel: usize)
+    {
+        self.num_primary_rays = samples_per_pixel.max(1) * NUM_PIXELS;
+    }
 }
 
 pub fn load_blue_noise_from_file(cl: &OpenCL, file: std::path::PathBuf) -> OpenCLBuffer<u8>
@@ -37,7 +91,182 @@ pub fn load_blue_noise_from_file(cl: &OpenCL, file: std::path::PathBuf) -> OpenC
         pixels[(y * img.height() + x) as usize] = r;
     }
 
-    return OpenCLBuffer::read_only(cl, pixels);
+    let blue_noise = OpenCLBuffer::read_only(cl, pixels);
+    blue_noise.copy_to_device(cl);
+    return blue_noise;
+}
+
+// the scene data that is bound to the kernels, each kernel takes the scene arguments it reads
+// after its own arguments, in the order of its list below
+#[derive(Copy, Clone)]
+enum SceneArgument
+{
+    NumObjects,
+    ObjMeshIds,
+    ObjMatIds,
+    ObjTransforms,
+    ObjInvTransforms,
+    BvhOffsets,
+    MeshOffsets,
+    BvhMinBounds,
+    BvhMaxBounds,
+    BvhTriCounts,
+    BvhLeftFirsts,
+    BvhTriangleOffsets,
+    BvhTriangles,
+    MeshVertexIds,
+    MeshVertexNormals,
+    MatOffsets,
+    MatColors,
+    MatReflectiveness,
+    MatRefractionIndices,
+    MatEmissions,
+    NumLights,
+    LightVertices,
+    LightEmissions,
+    LightAreas,
+    LightCdf,
+    NumPointLights,
+    PointLights,
+    NumSpotLights,
+    SpotLights,
+    NumDirectionalLights,
+    DirectionalLights,
+    EnvMapWidth,
+    EnvMapHeight,
+    EnvMapPixels,
+    EnvMapMarginalCdf,
+    EnvMapConditionalCdf,
+    MatRoughness,
+    MatMetallic,
+    MatSpecular,
+    MeshVertexUvs,
+    MatAlbedoTextures,
+    TexOffsets,
+    TexDimensions,
+    TexWrapModes,
+    TexPixels,
+    MeshVertexTangents,
+    MatNormalTextures,
+    MeshTriangleMatIds,
+    MatMedia,
+    Media,
+    FogMedium,
+    FogMinBound,
+    FogMaxBound,
+    NumMediumBoundaries,
+    LightTotalPower,
+    TlasMinBounds,
+    TlasMaxBounds,
+    TlasObjCounts,
+    TlasLeftFirsts,
+    TlasObjIds
+}
+
+// generate_rays and extend find the closest hit with its shading attributes
+const INTERSECT_SCENE_ARGUMENTS: &[SceneArgument] = &[
+    SceneArgument::NumObjects,
+    SceneArgument::ObjMeshIds,
+    SceneArgument::ObjMatIds,
+    SceneArgument::ObjTransforms,
+    SceneArgument::ObjInvTransforms,
+    SceneArgument::BvhOffsets,
+    SceneArgument::MeshOffsets,
+    SceneArgument::BvhMinBounds,
+    SceneArgument::BvhMaxBounds,
+    SceneArgument::BvhTriCounts,
+    SceneArgument::BvhLeftFirsts,
+    SceneArgument::BvhTriangleOffsets,
+    SceneArgument::BvhTriangles,
+    SceneArgument::MeshVertexIds,
+    SceneArgument::MeshVertexNormals,
+    SceneArgument::MatOffsets,
+    SceneArgument::MatColors,
+    SceneArgument::MeshVertexUvs,
+    SceneArgument::MatAlbedoTextures,
+    SceneArgument::TexOffsets,
+    SceneArgument::TexDimensions,
+    SceneArgument::TexWrapModes,
+    SceneArgument::TexPixels,
+    SceneArgument::MeshVertexTangents,
+    SceneArgument::MatNormalTextures,
+    SceneArgument::MeshTriangleMatIds,
+    SceneArgument::TlasMinBounds,
+    SceneArgument::TlasMaxBounds,
+    SceneArgument::TlasObjCounts,
+    SceneArgument::TlasLeftFirsts,
+    SceneArgument::TlasObjIds
+];
+
+// shade samples the lights and the materials and never traces a ray
+const SHADE_SCENE_ARGUMENTS: &[SceneArgument] = &[
+    SceneArgument::MatOffsets,
+    SceneArgument::MatReflectiveness,
+    SceneArgument::MatRefractionIndices,
+    SceneArgument::MatEmissions,
+    SceneArgument::NumLights,
+    SceneArgument::LightVertices,
+    SceneArgument::LightEmissions,
+    SceneArgument::LightAreas,
+    SceneArgument::LightCdf,
+    SceneArgument::NumPointLights,
+    SceneArgument::PointLights,
+    SceneArgument::NumSpotLights,
+    SceneArgument::SpotLights,
+    SceneArgument::NumDirectionalLights,
+    SceneArgument::DirectionalLights,
+    SceneArgument::EnvMapWidth,
+    SceneArgument::EnvMapHeight,
+    SceneArgument::EnvMapPixels,
+    SceneArgument::EnvMapMarginalCdf,
+    SceneArgument::EnvMapConditionalCdf,
+    SceneArgument::MatRoughness,
+    SceneArgument::MatMetallic,
+    SceneArgument::MatSpecular,
+    SceneArgument::MatMedia,
+    SceneArgument::Media,
+    SceneArgument::FogMedium,
+    SceneArgument::FogMinBound,
+    SceneArgument::FogMaxBound,
+    SceneArgument::LightTotalPower
+];
+
+// connect traces the shadow rays and only looks at the materials and media of the surfaces it hits
+const CONNECT_SCENE_ARGUMENTS: &[SceneArgument] = &[
+    SceneArgument::NumObjects,
+    SceneArgument::ObjMeshIds,
+    SceneArgument::ObjMatIds,
+    SceneArgument::ObjInvTransforms,
+    SceneArgument::BvhOffsets,
+    SceneArgument::MeshOffsets,
+    SceneArgument::BvhMinBounds,
+    SceneArgument::BvhMaxBounds,
+    SceneArgument::BvhTriCounts,
+    SceneArgument::BvhLeftFirsts,
+    SceneArgument::BvhTriangleOffsets,
+    SceneArgument::BvhTriangles,
+    SceneArgument::MatOffsets,
+    SceneArgument::MatRefractionIndices,
+    SceneArgument::MeshTriangleMatIds,
+    SceneArgument::MatMedia,
+    SceneArgument::Media,
+    SceneArgument::FogMedium,
+    SceneArgument::FogMinBound,
+    SceneArgument::FogMaxBound,
+    SceneArgument::NumMediumBoundaries,
+    SceneArgument::TlasMinBounds,
+    SceneArgument::TlasMaxBounds,
+    SceneArgument::TlasObjCounts,
+    SceneArgument::TlasLeftFirsts,
+    SceneArgument::TlasObjIds
+];
+
+// create a device buffer where every element is set to the same value
+fn zeroed_buffer<T: Clone>(cl: &OpenCL, value: T, len: usize) -> OpenCLBuffer<T>
+{
+    let buffer = OpenCLBuffer::read_write(cl, vec![value; len]);
+    buffer.copy_to_device(cl);
+    return buffer;
 }
 
 pub struct Renderer
@@ -53,6 +282,8 @@ pub struct Renderer
 
     // final kernels
     albedo_kernel: OpenCLKernel,
+    normals_kernel: OpenCLKernel,
+    path_tracing_kernel: OpenCLKernel,
     finalize_kernel: OpenCLKernel,
 
     num_rays: OpenCLBuffer<u32>,
@@ -61,20 +292,30 @@ pub struct Renderer
     ray_origins: OpenCLBuffer<Float3>,
     ray_directions: OpenCLBuffer<Float3>,
     ray_normals: OpenCLBuffer<Float3>,
+    ray_geometric_normals: OpenCLBuffer<Float3>,
+    ray_mat_ids: OpenCLBuffer<u32>,
+    ray_media: OpenCLBuffer<u32>,
+    ray_obj_ids: OpenCLBuffer<u32>,
 
     ray_energies: OpenCLBuffer<Float3>,
     ray_intersection_colors: OpenCLBuffer<Float3>,
     ray_write_back_lights: OpenCLBuffer<Float3>,
+    ray_flags: OpenCLBuffer<u32>,
 
     shadow_ray_ts: OpenCLBuffer<f32>,
     shadow_ray_origins: OpenCLBuffer<Float3>,
     shadow_ray_directions: OpenCLBuffer<Float3>,
     shadow_ray_write_back_ids: OpenCLBuffer<u32>,
     shadow_ray_write_back_lights: OpenCLBuffer<Float3>,
+    shadow_ray_media: OpenCLBuffer<u32>,
+    ray_bsdf_pdfs: OpenCLBuffer<f32>,
+    ray_environment_pdfs: OpenCLBuffer<f32>,
 
     albedo: OpenCLBuffer<Float3>,
     light: OpenCLBuffer<Float3>,
     light_accumulator: OpenCLBuffer<Float3>,
+    ray_filter_weights: OpenCLBuffer<f32>,
+    weight_accumulator: OpenCLBuffer<f32>,
 
     // render target
     pub output_buffer: OpenCLBuffer<u32>,
@@ -82,6 +323,7 @@ pub struct Renderer
     // rng
     seed: u32,
     blue_noise_texture: OpenCLBuffer<u8>,
+    frame_idx: u32,
 
     // for accumulation
     rendered_frames: u32,
@@ -108,137 +350,28 @@ impl Renderer
         let finalize_program = OpenCLProgram::from_file(cl, path::Path::new("./src/kernels/finalize.cl"));
         let finalize_kernel = OpenCLKernel::from_program(cl, &finalize_program, "finalize");
         let albedo_kernel = OpenCLKernel::from_program(cl, &finalize_program, "albedo");
+        let normals_kernel = OpenCLKernel::from_program(cl, &finalize_program, "normals");
+        let path_tracing_kernel = OpenCLKernel::from_program(cl, &finalize_program, "path_tracing");
 
         info!("generating ray kernels -- finished");
 
-        let num_primary_rays = SCRWIDTH * SCRHEIGHT;
-        let num_bounces = 10;
-
-        let mut num_rays: Vec<u32> = Vec::with_capacity(num_bounces * 2);
-        for _ in 0..(num_bounces + 1)
-        {
-            num_rays.push(0);
-            num_rays.push(0);
-        }
-        num_rays[0] = num_primary_rays as u32; // set initial rays
-
-        let mut ray_write_back_ids: Vec<u32> = Vec::new();
-        let mut ray_ts: Vec<f32> = Vec::new();
-        let mut ray_origins: Vec<Float3> = Vec::new();
-        let mut ray_directions: Vec<Float3> = Vec::new();
-        let mut ray_normals: Vec<Float3> = Vec::new();
-
-        let mut ray_energies: Vec<Float3> = Vec::new();
-        let mut ray_intersection_colors: Vec<Float3> = Vec::new();
-        let mut ray_write_back_lights: Vec<Float3> = Vec::new();
-
-        let mut shadow_ray_ts: Vec<f32> = Vec::new();
-        let mut shadow_ray_origins: Vec<Float3> = Vec::new();
-        let mut shadow_ray_directions: Vec<Float3> = Vec::new();
-        let mut shadow_ray_write_back_ids: Vec<u32> = Vec::new();
-        let mut shadow_ray_write_back_lights: Vec<Float3> = Vec::new();
-
-        let mut albedo: Vec<Float3> = Vec::new();
-        let mut light: Vec<Float3> = Vec::new();
-        let mut light_accumulator: Vec<Float3> = Vec::new();
-
-        // render target
-        let mut output_buffer: Vec<u32> = Vec::new();
-
-        // populate buffers
-        for _ in 0..num_primary_rays
-        {
-            ray_write_back_ids.push(0);
-            ray_ts.push(0.0);
-            ray_origins.push(Float3::zero());
-            ray_directions.push(Float3::zero());
-            ray_normals.push(Float3::zero());
-            output_buffer.push(0);
-            ray_energies.push(Float3::zero());
-            ray_intersection_colors.push(Float3::zero());
-            ray_write_back_lights.push(Float3::zero());
-            shadow_ray_write_back_ids.push(0);
-            shadow_ray_ts.push(0.0);
-            shadow_ray_origins.push(Float3::zero());
-            shadow_ray_directions.push(Float3::zero());
-            shadow_ray_write_back_lights.push(Float3::zero());
-            albedo.push(Float3::zero());
-        }
-
-        for _ in 0..(num_bounces + 1)
-        {
-            for _ in 0..num_primary_rays
-            {
-                light.push(Float3::zero());
-                light_accumulator.push(Float3::zero())
-            }
-        }
-
         let settings = RenderSettings
         {
-            render_mode: Albedo,
-            num_primary_rays,
-            num_bounces
+            render_mode: AccumulatedLight,
+            num_primary_rays: NUM_PIXELS,
+            num_bounces: 10,
+            light_layer: 0,
+            tone_mapping: ToneMapping::Aces,
+            exposure: 0.0,
+            russian_roulette_start: 2,
+            russian_roulette_max_survival: 0.95,
+            pixel_filter: PixelFilter::BlackmanHarris,
+            environment_rotation: 0.0,
+            environment_intensity: 1.0,
+            sampling_strategy: SamplingStrategy::MultipleImportance
         };
 
-        let num_rays = OpenCLBuffer::read_write(cl, num_rays);
-        let ray_write_back_ids = OpenCLBuffer::read_write(cl, ray_write_back_ids);
-        let ray_ts = OpenCLBuffer::read_write(cl, ray_ts);
-        let ray_origins = OpenCLBuffer::read_write(cl, ray_origins);
-        let ray_directions = OpenCLBuffer::read_write(cl, ray_directions);
-        let ray_normals = OpenCLBuffer::read_write(cl, ray_normals);
-        let ray_energies = OpenCLBuffer::read_write(cl, ray_energies);
-        let ray_intersection_colors = OpenCLBuffer::read_write(cl, ray_intersection_colors);
-        let ray_write_back_lights = OpenCLBuffer::read_write(cl, ray_write_back_lights);
-        let shadow_ray_ts = OpenCLBuffer::read_write(cl, shadow_ray_ts);
-        let shadow_ray_origins = OpenCLBuffer::read_write(cl, shadow_ray_origins);
-        let shadow_ray_directions = OpenCLBuffer::read_write(cl, shadow_ray_directions);
-        let shadow_ray_write_back_ids = OpenCLBuffer::read_write(cl, shadow_ray_write_back_ids);
-        let shadow_ray_write_back_lights = OpenCLBuffer::read_write(cl, shadow_ray_write_back_lights);
-        let albedo = OpenCLBuffer::read_write(cl, albedo);
-        let light = OpenCLBuffer::read_write(cl, light);
-        let light_accumulator = OpenCLBuffer::read_write(cl, light_accumulator);
-        let output_buffer = OpenCLBuffer::read_write(cl, output_buffer);
-
-        num_rays.copy_to_device(cl);
-        ray_write_back_ids.copy_to_device(cl);
-        ray_ts.copy_to_device(cl);
-        ray_origins.copy_to_device(cl);
-        ray_directions.copy_to_device(cl);
-        ray_normals.copy_to_device(cl);
-        ray_energies.copy_to_device(cl);
-        ray_intersection_colors.copy_to_device(cl);
-        ray_write_back_lights.copy_to_device(cl);
-        shadow_ray_ts.copy_to_device(cl);
-        shadow_ray_origins.copy_to_device(cl);
-        shadow_ray_directions.copy_to_device(cl);
-        shadow_ray_write_back_ids.copy_to_device(cl);
-        shadow_ray_write_back_lights.copy_to_device(cl);
-        albedo.copy_to_device(cl);
-        light.copy_to_device(cl);
-        light_accumulator.copy_to_device(cl);
-        output_buffer.copy_to_device(cl);
-
-        generate_rays_kernel.set_argument(0, 320340458);
-        generate_rays_kernel.set_argument(1, SCRWIDTH as u32);
-        generate_rays_kernel.set_argument(2, SCRHEIGHT as u32);
-        generate_rays_kernel.set_argument(3, num_bounces as u32);
-
-        generate_rays_kernel.set_argument(8, &ray_write_back_ids);
-        generate_rays_kernel.set_argument(9, &ray_ts);
-        generate_rays_kernel.set_argument(10, &ray_origins);
-        generate_rays_kernel.set_argument(11, &ray_directions);
-        generate_rays_kernel.set_argument(12, &ray_normals);
-        generate_rays_kernel.set_argument(13, &ray_intersection_colors);
-        generate_rays_kernel.set_argument(14, &ray_energies);
-        generate_rays_kernel.set_argument(15, &ray_write_back_lights);
-        generate_rays_kernel.set_argument(16, &albedo);
-        generate_rays_kernel.set_argument(17, &light);
-
-        albedo_kernel.set_argument(0, &albedo);
-        albedo_kernel.set_argument(1, &output_buffer);
-
-        return Renderer{
+        let mut renderer = Renderer{
             settings,
             last_settings: settings,
             generate_rays_kernel,
@@ -247,37 +380,236 @@ impl Renderer
             connect_kernel,
             finalize_kernel,
             albedo_kernel,
-            num_rays,
-            ray_write_back_ids,
-            ray_ts,
-            ray_origins,
-            ray_directions,
-            ray_normals,
-
-            ray_energies,
-            ray_intersection_colors,
-            ray_write_back_lights,
-
-            shadow_ray_ts,
-            shadow_ray_origins,
-            shadow_ray_directions,
-            shadow_ray_write_back_ids,
-            shadow_ray_write_back_lights,
-
-            albedo,
-            light,
-            light_accumulator,
+            normals_kernel,
+            path_tracing_kernel,
+            num_rays: Renderer::create_num_rays(cl, &settings),
+
+            ray_write_back_ids: zeroed_buffer(cl, 0, settings.num_primary_rays * 2),
+            ray_ts: zeroed_buffer(cl, 0.0, settings.num_primary_rays * 2),
+            ray_origins: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays * 2),
+            ray_directions: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays * 2),
+            ray_normals: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays * 2),
+            ray_geometric_normals: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays * 2),
+            ray_mat_ids: zeroed_buffer(cl, 0, settings.num_primary_rays * 2),
+            ray_media: zeroed_buffer(cl, 0, settings.num_primary_rays * 2),
+            ray_obj_ids: zeroed_buffer(cl, 0, settings.num_primary_rays * 2),
+
+            ray_energies: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays * 2),
+            ray_intersection_colors: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays * 2),
+            ray_write_back_lights: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays * 2),
+            ray_flags: zeroed_buffer(cl, 0, settings.num_primary_rays * 2),
+
+            shadow_ray_ts: zeroed_buffer(cl, 0.0, settings.num_primary_rays),
+            shadow_ray_origins: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays),
+            shadow_ray_directions: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays),
+            shadow_ray_write_back_ids: zeroed_buffer(cl, 0, settings.num_primary_rays),
+            shadow_ray_write_back_lights: zeroed_buffer(cl, Float3::zero(), settings.num_primary_rays),
+            shadow_ray_media: zeroed_buffer(cl, 0, settings.num_primary_rays),
+            ray_bsdf_pdfs: zeroed_buffer(cl, 0.0, settings.num_primary_rays * 2),
+            ray_environment_pdfs: zeroed_buffer(cl, 0.0, settings.num_primary_rays * 2),
+
+            albedo: zeroed_buffer(cl, Float3::zero(), NUM_PIXELS),
+            light: zeroed_buffer(cl, Float3::zero(), (settings.num_bounces + 1) * settings.num_primary_rays),
+            light_accumulator: zeroed_buffer(cl, Float3::zero(), (settings.num_bounces + 1) * NUM_PIXELS),
+            ray_filter_weights: zeroed_buffer(cl, 0.0, settings.num_primary_rays),
+            weight_accumulator: zeroed_buffer(cl, 0.0, NUM_PIXELS),
 
             // render target
-            output_buffer,
+            output_buffer: zeroed_buffer(cl, 0, NUM_PIXELS),
 
             // rng
             seed: 320340458,
             blue_noise_texture: load_blue_noise_from_file(cl, std::path::PathBuf::from("./assets/blue_noise.png")),
+            frame_idx: 0,
 
             // for accumulation
             rendered_frames: 1,
+        };
+
+        renderer.generate_rays_kernel.set_argument(0, renderer.seed);
+        renderer.generate_rays_kernel.set_argument(1, SCRWIDTH as u32);
+        renderer.generate_rays_kernel.set_argument(2, SCRHEIGHT as u32);
+        renderer.generate_rays_kernel.set_argument(10, &renderer.blue_noise_texture);
+        renderer.set_buffer_arguments();
+
+        return renderer;
+    }
+
+    // the ray counters hold the number of rays and shadow rays for every bounce,
+    // the primary rays are always all in the queue
+    fn create_num_rays(cl: &OpenCL, settings: &RenderSettings) -> OpenCLBuffer<u32>
+    {
+        let mut num_rays: Vec<u32> = vec![0; (settings.num_bounces + 1) * 2];
+        num_rays[0] = settings.num_primary_rays as u32; // set initial rays
+
+        let num_rays = OpenCLBuffer::read_write(cl, num_rays);
+        num_rays.copy_to_device(cl);
+        return num_rays;
+    }
+
+    // bind all buffers owned by the renderer and the settings they depend on to the kernels
+    fn set_buffer_arguments(&mut self)
+    {
+        let num_bounces = self.settings.num_bounces as u32;
+        let num_primary_rays = self.settings.num_primary_rays as u32;
+
+        self.generate_rays_kernel.set_argument(3, num_bounces);
+
+        self.generate_rays_kernel.set_argument(11, &self.ray_write_back_ids);
+        self.generate_rays_kernel.set_argument(12, &self.ray_ts);
+        self.generate_rays_kernel.set_argument(13, &self.ray_origins);
+        self.generate_rays_kernel.set_argument(14, &self.ray_directions);
+        self.generate_rays_kernel.set_argument(15, &self.ray_normals);
+        self.generate_rays_kernel.set_argument(16, &self.ray_obj_ids);
+        self.generate_rays_kernel.set_argument(17, &self.ray_intersection_colors);
+        self.generate_rays_kernel.set_argument(18, &self.ray_energies);
+        self.generate_rays_kernel.set_argument(19, &self.ray_write_back_lights);
+        self.generate_rays_kernel.set_argument(20, &self.albedo);
+        self.generate_rays_kernel.set_argument(21, &self.light);
+        self.generate_rays_kernel.set_argument(22, &self.ray_filter_weights);
+        self.generate_rays_kernel.set_argument(23, &self.ray_flags);
+        self.generate_rays_kernel.set_argument(24, &self.ray_geometric_normals);
+        self.generate_rays_kernel.set_argument(25, &self.ray_mat_ids);
+        self.generate_rays_kernel.set_argument(26, &self.ray_media);
+
+        self.extend_kernel.set_argument(1, num_primary_rays);
+        self.extend_kernel.set_argument(2, &self.num_rays);
+        self.extend_kernel.set_argument(3, &self.ray_ts);
+        self.extend_kernel.set_argument(4, &self.ray_origins);
+        self.extend_kernel.set_argument(5, &self.ray_directions);
+        self.extend_kernel.set_argument(6, &self.ray_normals);
+        self.extend_kernel.set_argument(7, &self.ray_intersection_colors);
+        self.extend_kernel.set_argument(8, &self.ray_obj_ids);
+        self.extend_kernel.set_argument(9, &self.ray_geometric_normals);
+        self.extend_kernel.set_argument(10, &self.ray_mat_ids);
+
+        self.shade_kernel.set_argument(1, num_bounces);
+        self.shade_kernel.set_argument(2, num_primary_rays);
+        self.shade_kernel.set_argument(6, &self.num_rays);
+        self.shade_kernel.set_argument(7, &self.ray_write_back_ids);
+        self.shade_kernel.set_argument(8, &self.ray_ts);
+        self.shade_kernel.set_argument(9, &self.ray_origins);
+        self.shade_kernel.set_argument(10, &self.ray_directions);
+        self.shade_kernel.set_argument(11, &self.ray_normals);
+        self.shade_kernel.set_argument(12, &self.ray_obj_ids);
+        self.shade_kernel.set_argument(13, &self.ray_energies);
+        self.shade_kernel.set_argument(14, &self.light);
+        self.shade_kernel.set_argument(15, &self.shadow_ray_ts);
+        self.shade_kernel.set_argument(16, &self.shadow_ray_origins);
+        self.shade_kernel.set_argument(17, &self.shadow_ray_directions);
+        self.shade_kernel.set_argument(18, &self.shadow_ray_write_back_ids);
+        self.shade_kernel.set_argument(19, &self.shadow_ray_write_back_lights);
+        self.shade_kernel.set_argument(20, &self.ray_flags);
+        self.shade_kernel.set_argument(23, &self.ray_intersection_colors);
+        self.shade_kernel.set_argument(24, &self.ray_geometric_normals);
+        self.shade_kernel.set_argument(25, &self.ray_mat_ids);
+        self.shade_kernel.set_argument(26, &self.ray_media);
+        self.shade_kernel.set_argument(27, &self.shadow_ray_media);
+        self.shade_kernel.set_argument(29, &self.ray_bsdf_pdfs);
+        self.shade_kernel.set_argument(30, &self.ray_environment_pdfs);
+
+        self.connect_kernel.set_argument(1, num_primary_rays);
+        self.connect_kernel.set_argument(2, &self.num_rays);
+        self.connect_kernel.set_argument(3, &self.shadow_ray_ts);
+        self.connect_kernel.set_argument(4, &self.shadow_ray_origins);
+        self.connect_kernel.set_argument(5, &self.shadow_ray_directions);
+        self.connect_kernel.set_argument(6, &self.shadow_ray_write_back_ids);
+        self.connect_kernel.set_argument(7, &self.shadow_ray_write_back_lights);
+        self.connect_kernel.set_argument(8, &self.light);
+        self.connect_kernel.set_argument(9, &self.shadow_ray_media);
+
+        self.albedo_kernel.set_argument(0, &self.albedo);
+        self.albedo_kernel.set_argument(1, &self.output_buffer);
+
+        self.normals_kernel.set_argument(0, &self.ray_normals);
+        self.normals_kernel.set_argument(1, &self.output_buffer);
+
+        self.path_tracing_kernel.set_argument(2, num_bounces);
+        self.path_tracing_kernel.set_argument(3, num_primary_rays);
+        self.path_tracing_kernel.set_argument(4, &self.light);
+        self.path_tracing_kernel.set_argument(5, &self.ray_filter_weights);
+        self.path_tracing_kernel.set_argument(6, &self.output_buffer);
+
+        self.finalize_kernel.set_argument(4, num_bounces);
+        self.finalize_kernel.set_argument(5, num_primary_rays);
+        self.finalize_kernel.set_argument(6, &self.light);
+        self.finalize_kernel.set_argument(7, &self.ray_filter_weights);
+        self.finalize_kernel.set_argument(8, &self.light_accumulator);
+        self.finalize_kernel.set_argument(9, &self.weight_accumulator);
+        self.finalize_kernel.set_argument(10, &self.output_buffer);
+    }
+
+    // compare the settings against the settings of the last frame,
+    // reallocate the buffers whose size depends on changed settings and rebind them
+    pub fn apply_settings(&mut self, cl: &OpenCL)
+    {
+        // every pixel gets the same amount of primary rays
+        let samples_per_pixel = ((self.settings.num_primary_rays + NUM_PIXELS / 2) / NUM_PIXELS).max(1);
+        self.settings.num_primary_rays = samples_per_pixel * NUM_PIXELS;
+        self.settings.num_bounces = self.settings.num_bounces.max(1);
+        self.settings.light_layer = self.settings.light_layer.min(self.settings.num_bounces);
+
+        if self.settings == self.last_settings
+        {
+            return;
         }
+
+        let rays_changed = self.settings.num_primary_rays != self.last_settings.num_primary_rays;
+        let bounces_changed = self.settings.num_bounces != self.last_settings.num_bounces;
+
+        if rays_changed
+        {
+            let num_primary_rays = self.settings.num_primary_rays;
+            info!("reallocating ray buffers for {} primary rays", num_primary_rays);
+
+            self.ray_write_back_ids = zeroed_buffer(cl, 0, num_primary_rays * 2);
+            self.ray_ts = zeroed_buffer(cl, 0.0, num_primary_rays * 2);
+            self.ray_origins = zeroed_buffer(cl, Float3::zero(), num_primary_rays * 2);
+            self.ray_directions = zeroed_buffer(cl, Float3::zero(), num_primary_rays * 2);
+            self.ray_normals = zeroed_buffer(cl, Float3::zero(), num_primary_rays * 2);
+            self.ray_geometric_normals = zeroed_buffer(cl, Float3::zero(), num_primary_rays * 2);
+            self.ray_mat_ids = zeroed_buffer(cl, 0, num_primary_rays * 2);
+            self.ray_media = zeroed_buffer(cl, 0, num_primary_rays * 2);
+            self.ray_obj_ids = zeroed_buffer(cl, 0, num_primary_rays * 2);
+
+            self.ray_energies = zeroed_buffer(cl, Float3::zero(), num_primary_rays * 2);
+            self.ray_intersection_colors = zeroed_buffer(cl, Float3::zero(), num_primary_rays * 2);
+            self.ray_write_back_lights = zeroed_buffer(cl, Float3::zero(), num_primary_rays * 2);
+            self.ray_flags = zeroed_buffer(cl, 0, num_primary_rays * 2);
+
+            self.shadow_ray_ts = zeroed_buffer(cl, 0.0, num_primary_rays);
+            self.shadow_ray_origins = zeroed_buffer(cl, Float3::zero(), num_primary_rays);
+            self.shadow_ray_directions = zeroed_buffer(cl, Float3::zero(), num_primary_rays);
+            self.shadow_ray_write_back_ids = zeroed_buffer(cl, 0, num_primary_rays);
+            self.shadow_ray_write_back_lights = zeroed_buffer(cl, Float3::zero(), num_primary_rays);
+            self.shadow_ray_media = zeroed_buffer(cl, 0, num_primary_rays);
+            self.ray_bsdf_pdfs = zeroed_buffer(cl, 0.0, num_primary_rays * 2);
+            self.ray_environment_pdfs = zeroed_buffer(cl, 0.0, num_primary_rays * 2);
+            self.ray_filter_weights = zeroed_buffer(cl, 0.0, num_primary_rays);
+        }
+
+        if rays_changed || bounces_changed
+        {
+            info!("reallocating light buffers for {} bounces", self.settings.num_bounces);
+
+            let num_layers = self.settings.num_bounces + 1;
+            self.num_rays = Renderer::create_num_rays(cl, &self.settings);
+            self.light = zeroed_buffer(cl, Float3::zero(), num_layers * self.settings.num_primary_rays);
+            self.light_accumulator = zeroed_buffer(cl, Float3::zero(), num_layers * NUM_PIXELS);
+
+            self.set_buffer_arguments();
+        }
+
+        // the display transform is applied after accumulation, so changing it keeps the accumulated light
+        let mut light_settings = self.settings;
+        light_settings.tone_mapping = self.last_settings.tone_mapping;
+        light_settings.exposure = self.last_settings.exposure;
+        if light_settings != self.last_settings
+        {
+            self.reset_accumulation();
+        }
+
+        self.last_settings = self.settings;
     }
 
     pub fn set_camera(&mut self, camera: &Camera)
@@ -286,41 +618,174 @@ impl Renderer
         self.generate_rays_kernel.set_argument(5, &camera.top_left);
         self.generate_rays_kernel.set_argument(6, &camera.bottom_left);
         self.generate_rays_kernel.set_argument(7, &camera.top_right);
+        self.reset_accumulation();
+    }
+
+    // restart the running average, the next frame overwrites the accumulated light
+    pub fn reset_accumulation(&mut self)
+    {
         self.rendered_frames = 1;
     }
 
+    // bind the scene arguments of a kernel, they are the last parameters of the kernel
+    fn set_scene_arguments(kernel: &OpenCLKernel, arguments: &[SceneArgument], scene: &Scene)
+    {
+        let num_arguments = kernel.num_arguments();
+        assert!(arguments.len() as u32 <= num_arguments, "Kernel takes fewer arguments than its scene arguments");
+        let first_idx = num_arguments - arguments.len() as u32;
+
+        for (i, argument) in arguments.iter().enumerate()
+        {
+            let idx = first_idx + i as u32;
+            match argument
+            {
+                SceneArgument::NumObjects => kernel.set_argument(idx, scene.obj_mesh_ids.host_buffer.len() as u32),
+                SceneArgument::ObjMeshIds => kernel.set_argument(idx, &scene.obj_mesh_ids),
+                SceneArgument::ObjMatIds => kernel.set_argument(idx, &scene.obj_mat_ids),
+                SceneArgument::ObjTransforms => kernel.set_argument(idx, &scene.obj_transforms),
+                SceneArgument::ObjInvTransforms => kernel.set_argument(idx, &scene.obj_inv_transforms),
+                SceneArgument::BvhOffsets => kernel.set_argument(idx, &scene.bvh_offsets),
+                SceneArgument::MeshOffsets => kernel.set_argument(idx, &scene.mesh_offsets),
+                SceneArgument::BvhMinBounds => kernel.set_argument(idx, &scene.bvh_min_bounds),
+                SceneArgument::BvhMaxBounds => kernel.set_argument(idx, &scene.bvh_max_bounds),
+                SceneArgument::BvhTriCounts => kernel.set_argument(idx, &scene.bvh_tri_counts),
+                SceneArgument::BvhLeftFirsts => kernel.set_argument(idx, &scene.bvh_left_firsts),
+                SceneArgument::BvhTriangleOffsets => kernel.set_argument(idx, &scene.bvh_triangle_offsets),
+                SceneArgument::BvhTriangles => kernel.set_argument(idx, &scene.bvh_triangles),
+                SceneArgument::MeshVertexIds => kernel.set_argument(idx, &scene.mesh_vertex_ids),
+                SceneArgument::MeshVertexNormals => kernel.set_argument(idx, &scene.mesh_vertex_normals),
+                SceneArgument::MatOffsets => kernel.set_argument(idx, &scene.mat_offsets),
+                SceneArgument::MatColors => kernel.set_argument(idx, &scene.mat_colors),
+                SceneArgument::MatReflectiveness => kernel.set_argument(idx, &scene.mat_reflectiveness),
+                SceneArgument::MatRefractionIndices => kernel.set_argument(idx, &scene.mat_refraction_indices),
+                SceneArgument::MatEmissions => kernel.set_argument(idx, &scene.mat_emissions),
+                SceneArgument::NumLights => kernel.set_argument(idx, scene.num_lights),
+                SceneArgument::LightVertices => kernel.set_argument(idx, &scene.light_vertices),
+                SceneArgument::LightEmissions => kernel.set_argument(idx, &scene.light_emissions),
+                SceneArgument::LightAreas => kernel.set_argument(idx, &scene.light_areas),
+                SceneArgument::LightCdf => kernel.set_argument(idx, &scene.light_cdf),
+                SceneArgument::NumPointLights => kernel.set_argument(idx, scene.num_point_lights),
+                SceneArgument::PointLights => kernel.set_argument(idx, &scene.point_lights),
+                SceneArgument::NumSpotLights => kernel.set_argument(idx, scene.num_spot_lights),
+                SceneArgument::SpotLights => kernel.set_argument(idx, &scene.spot_lights),
+                SceneArgument::NumDirectionalLights => kernel.set_argument(idx, scene.num_directional_lights),
+                SceneArgument::DirectionalLights => kernel.set_argument(idx, &scene.directional_lights),
+                SceneArgument::EnvMapWidth => kernel.set_argument(idx, scene.env_map_width),
+                SceneArgument::EnvMapHeight => kernel.set_argument(idx, scene.env_map_height),
+                SceneArgument::EnvMapPixels => kernel.set_argument(idx, &scene.env_map_pixels),
+                SceneArgument::EnvMapMarginalCdf => kernel.set_argument(idx, &scene.env_map_marginal_cdf),
+                SceneArgument::EnvMapConditionalCdf => kernel.set_argument(idx, &scene.env_map_conditional_cdf),
+                SceneArgument::MatRoughness => kernel.set_argument(idx, &scene.mat_roughness),
+                SceneArgument::MatMetallic => kernel.set_argument(idx, &scene.mat_metallic),
+                SceneArgument::MatSpecular => kernel.set_argument(idx, &scene.mat_specular),
+                SceneArgument::MeshVertexUvs => kernel.set_argument(idx, &scene.mesh_vertex_uvs),
+                SceneArgument::MatAlbedoTextures => kernel.set_argument(idx, &scene.mat_albedo_textures),
+                SceneArgument::TexOffsets => kernel.set_argument(idx, &scene.tex_offsets),
+                SceneArgument::TexDimensions => kernel.set_argument(idx, &scene.tex_dimensions),
+                SceneArgument::TexWrapModes => kernel.set_argument(idx, &scene.tex_wrap_modes),
+                SceneArgument::TexPixels => kernel.set_argument(idx, &scene.tex_pixels),
+                SceneArgument::MeshVertexTangents => kernel.set_argument(idx, &scene.mesh_vertex_tangents),
+                SceneArgument::MatNormalTextures => kernel.set_argument(idx, &scene.mat_normal_textures),
+                SceneArgument::MeshTriangleMatIds => kernel.set_argument(idx, &scene.mesh_triangle_mat_ids),
+                SceneArgument::MatMedia => kernel.set_argument(idx, &scene.mat_media),
+                SceneArgument::Media => kernel.set_argument(idx, &scene.media),
+                SceneArgument::FogMedium => kernel.set_argument(idx, scene.fog_medium),
+                SceneArgument::FogMinBound => kernel.set_argument(idx, &scene.fog_min_bound),
+                SceneArgument::FogMaxBound => kernel.set_argument(idx, &scene.fog_max_bound),
+                SceneArgument::NumMediumBoundaries => kernel.set_argument(idx, scene.num_medium_boundaries),
+                SceneArgument::LightTotalPower => kernel.set_argument(idx, scene.light_total_power),
+                SceneArgument::TlasMinBounds => kernel.set_argument(idx, &scene.tlas_min_bounds),
+                SceneArgument::TlasMaxBounds => kernel.set_argument(idx, &scene.tlas_max_bounds),
+                SceneArgument::TlasObjCounts => kernel.set_argument(idx, &scene.tlas_obj_counts),
+                SceneArgument::TlasLeftFirsts => kernel.set_argument(idx, &scene.tlas_left_firsts),
+                SceneArgument::TlasObjIds => kernel.set_argument(idx, &scene.tlas_obj_ids),
+            }
+        }
+    }
+
     pub fn set_scene(&mut self, scene: &Scene)
     {
-        self.generate_rays_kernel.set_argument(18, scene.obj_mesh_ids.host_buffer.len() as u32);
-        self.generate_rays_kernel.set_argument(19, &scene.obj_mesh_ids);
-        self.generate_rays_kernel.set_argument(20, &scene.obj_mat_ids);
-        self.generate_rays_kernel.set_argument(21, &scene.obj_transforms);
-        self.generate_rays_kernel.set_argument(22, &scene.obj_inv_transforms);
-        self.generate_rays_kernel.set_argument(23, &scene.bvh_offsets);
-        self.generate_rays_kernel.set_argument(24, &scene.mesh_offsets);
-        self.generate_rays_kernel.set_argument(25, &scene.bvh_min_bounds);
-        self.generate_rays_kernel.set_argument(26, &scene.bvh_max_bounds);
-        self.generate_rays_kernel.set_argument(27, &scene.bvh_tri_counts);
-        self.generate_rays_kernel.set_argument(28, &scene.bvh_left_firsts);
-        self.generate_rays_kernel.set_argument(29, &scene.bvh_triangle_offsets);
-        self.generate_rays_kernel.set_argument(30, &scene.bvh_triangles);
-        self.generate_rays_kernel.set_argument(31, &scene.mesh_vertex_ids);
-        self.generate_rays_kernel.set_argument(32, &scene.mesh_vertex_normals);
-        self.generate_rays_kernel.set_argument(33, &scene.mat_offsets);
-        self.generate_rays_kernel.set_argument(34, &scene.mat_colors);
-        self.generate_rays_kernel.set_argument(35, &scene.mat_reflectiveness);
-        self.generate_rays_kernel.set_argument(36, &scene.mat_refraction_indices);
+        Renderer::set_scene_arguments(&self.generate_rays_kernel, INTERSECT_SCENE_ARGUMENTS, scene);
+        Renderer::set_scene_arguments(&self.extend_kernel, INTERSECT_SCENE_ARGUMENTS, scene);
+        Renderer::set_scene_arguments(&self.shade_kernel, SHADE_SCENE_ARGUMENTS, scene);
+        Renderer::set_scene_arguments(&self.connect_kernel, CONNECT_SCENE_ARGUMENTS, scene);
+        self.reset_accumulation();
     }
 
     pub fn render(&mut self, cl: &OpenCL, scene: &Scene)
     {
+        self.apply_settings(cl);
 
         self.generate_rays_kernel.set_argument(0, self.seed);
+        self.generate_rays_kernel.set_argument(8, self.frame_idx);
+        self.generate_rays_kernel.set_argument(9, self.settings.pixel_filter as u32);
+        self.shade_kernel.set_argument(3, self.seed);
+        self.shade_kernel.set_argument(4, self.settings.russian_roulette_start as u32);
+        self.shade_kernel.set_argument(5, self.settings.russian_roulette_max_survival);
+        self.shade_kernel.set_argument(21, self.settings.environment_rotation);
+        self.shade_kernel.set_argument(22, self.settings.environment_intensity);
+        self.shade_kernel.set_argument(28, self.settings.sampling_strategy as u32);
         random_uint_s(&mut self.seed);
+        self.frame_idx = self.frame_idx.wrapping_add(1);
 
-        self.generate_rays_kernel.run2d(cl, SCRWIDTH, SCRHEIGHT);
+        // primary rays are generated and intersected in one go, every sample of a pixel gets its own rows
+        self.generate_rays_kernel.run2d(cl, SCRWIDTH, self.settings.num_primary_rays / SCRWIDTH);
 
-        self.albedo_kernel.run(cl, self.settings.num_primary_rays);
+        // the albedo and normals only need the primary hits
+        let trace_paths = match self.settings.render_mode
+        {
+            RenderMode::Albedo | RenderMode::Normals => false,
+            _ => true
+        };
+
+        // shade the hits of every bounce, trace their shadow rays and the continuation rays,
+        // kernels exit early for rays that are not in the queue
+        if trace_paths
+        {
+            for bounce in 0..(self.settings.num_bounces + 1)
+            {
+                self.shade_kernel.set_argument(0, bounce as u32);
+                self.shade_kernel.run(cl, self.settings.num_primary_rays);
+
+                self.connect_kernel.set_argument(0, bounce as u32);
+                self.connect_kernel.run(cl, self.settings.num_primary_rays);
+
+                if bounce < self.settings.num_bounces
+                {
+                    self.extend_kernel.set_argument(0, (bounce + 1) as u32);
+                    self.extend_kernel.run(cl, self.settings.num_primary_rays);
+                }
+            }
+        }
+
+        let tone_mapping = self.settings.tone_mapping as u32;
+        let exposure = self.settings.exposure;
+
+        match self.settings.render_mode
+        {
+            RenderMode::PathTracing =>
+                {
+                    self.path_tracing_kernel.set_argument(0, tone_mapping);
+                    self.path_tracing_kernel.set_argument(1, exposure);
+                    self.path_tracing_kernel.run(cl, NUM_PIXELS);
+                }
+            RenderMode::Normals => self.normals_kernel.run(cl, NUM_PIXELS),
+            RenderMode::Albedo => self.albedo_kernel.run(cl, NUM_PIXELS),
+            RenderMode::AccumulatedLight | RenderMode::LightLayer =>
+                {
+                    let light_layer = match self.settings.render_mode
+                    {
+                        RenderMode::LightLayer => self.settings.light_layer.min(self.settings.num_bounces) as u32,
+                        _ => u32::MAX
+                    };
+                    self.finalize_kernel.set_argument(0, self.rendered_frames);
+                    self.finalize_kernel.set_argument(1, light_layer);
+                    self.finalize_kernel.set_argument(2, tone_mapping);
+                    self.finalize_kernel.set_argument(3, exposure);
+                    self.finalize_kernel.run(cl, NUM_PIXELS);
+                    self.rendered_frames += 1;
+                }
+        }
 
         self.output_buffer.copy_from_device(cl);
 
@@ -328,4 +793,3 @@ impl Renderer
         self.num_rays.copy_to_device(cl);
     }
 }
-
@@ -1,10 +1,23 @@
 
+use log::{info, warn};
 use std::f32::consts::PI;
 use crate::material::*;
+use crate::light::*;
+use crate::environment::EnvironmentMap;
+use crate::texture::Texture;
+use crate::medium::*;
+use crate::tlas::TLAS;
 use crate::math::*;
 use crate::render_components::*;
+use crate::bvh_construction::BVHBuildOptions;
 use crate::obj_loader::*;
 use crate::opencl::{OpenCL, OpenCLBuffer};
+use crate::bvh_cache;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+
+// built bvhs are stored here and loaded again as long as the mesh and the build options stay the same
+const BVH_CACHE_DIR: &str = "./cache/bvh";
 
 pub struct SceneObject
 {
@@ -33,7 +46,23 @@ pub struct SceneDescription
 {
     pub root_objects: Vec<SceneObject>,
     pub meshes: Vec<Mesh>,
-    pub materials: Vec<Material>
+
+    // the bvh of every mesh is built with the options at the index of the mesh
+    pub bvh_options: Vec<BVHBuildOptions>,
+
+    // where built bvhs are cached, none builds every bvh on every load,
+    // files of meshes the scene does not use are removed, so scenes should not share a directory
+    pub bvh_cache_dir: Option<PathBuf>,
+    pub materials: Vec<Material>,
+    pub point_lights: Vec<PointLight>,
+    pub spot_lights: Vec<SpotLight>,
+    pub directional_lights: Vec<DirectionalLight>,
+    pub environment_map: Option<EnvironmentMap>,
+    pub textures: Vec<Texture>,
+
+    // media are attached to closed meshes through their material, the fog fills the rest of its bounds
+    pub media: Vec<Medium>,
+    pub fog: Option<Fog>
 }
 
 
@@ -41,40 +70,79 @@ impl SceneDescription
 {
     pub fn new() -> Self
     {
-        let mut materials = vec![];
-
-        let mut meshes: Vec<Mesh> = Vec::new();
+        let mut scene = SceneDescription{
+            root_objects: Vec::new(),
+            meshes: Vec::new(),
+            bvh_options: Vec::new(),
+            bvh_cache_dir: Some(PathBuf::from(BVH_CACHE_DIR)),
+            materials: Vec::new(),
+            point_lights: Vec::new(),
+            spot_lights: Vec::new(),
+            directional_lights: Vec::new(),
+            environment_map: None,
+            textures: Vec::new(),
+            media: Vec::new(),
+            fog: None
+        };
 
-        let mut root_objects: Vec<SceneObject> = Vec::new();
+        // used for meshes without a material
+        scene.materials.push(Material::from_color(Float3::from_xyz(1.0,0.0,0.0)));
 
         let transform = Mat4::translate( &Float3::from_xyz(2.0, 0.0, 0.5)) * Mat4::scale(0.5);
-        let (msh, mts) = load_obj(&std::path::Path::new("./assets/suzanne.obj"));
+        scene.add_obj(&std::path::Path::new("./assets/suzanne.obj"), transform, 0, BVHBuildOptions::spatial());
 
-        root_objects.push(SceneObject::new(1, 0, transform, vec![]));
+        // thin haze around the model that mostly scatters forwards
+        scene.fog = Some(Fog{
+            medium: Medium::from_albedo(Float3::from_a(0.9), 0.02, 0.3),
+            bounds: AABB::from_bounds(&Float3::from_a(-20.0), &Float3::from_a(20.0))
+        });
 
-        for mesh in msh
+        return scene;
+    }
+
+    // the sky gradient stays in use when the environment map cannot be loaded
+    pub fn set_environment_map(&mut self, path: &std::path::Path)
+    {
+        match EnvironmentMap::from_file(path)
         {
-            meshes.push(mesh);
+            Ok(environment_map) => self.environment_map = Some(environment_map),
+            Err(error) => warn!("could not load environment map {}, using the sky gradient: {}", path.display(), error)
         }
+    }
+
+    // load an obj file and add every object in it as a root object,
+    // triangles without a material get the default material and every mesh is built with the given bvh options
+    pub fn add_obj(&mut self, path: &std::path::Path, transform: Mat4, default_mat_idx: u32, bvh_options: BVHBuildOptions)
+    {
+        let (meshes, materials, textures) = load_obj(path);
 
-        for material in mts
+        // the loaded indices are local to the file
+        let mesh_offset = self.meshes.len() as u32;
+        let mat_offset = self.materials.len() as u32;
+        let texture_offset = self.textures.len() as u32;
+
+        for (i, mut mesh) in meshes.into_iter().enumerate()
         {
-            materials.push(material);
+            for mat_idx in &mut mesh.triangle_material_ids
+            {
+                if *mat_idx != u32::MAX
+                {
+                    *mat_idx += mat_offset;
+                }
+            }
+            self.root_objects.push(SceneObject::new(mesh_offset + i as u32, default_mat_idx, transform, vec![]));
+            self.meshes.push(mesh);
+            self.bvh_options.push(bvh_options);
         }
 
-        materials.push(Material{
-            colors: vec![Float3::from_xyz(1.0,0.0,0.0)],
-            reflectiveness: vec![0.0],
-            refractive_indices: vec![0.0]
-        });
-
-        let mut scene = SceneDescription{
-            root_objects,
-            meshes,
-            materials
-        };
+        for mut material in materials
+        {
+            material.albedo_texture = material.albedo_texture.map(|idx| idx + texture_offset);
+            material.normal_texture = material.normal_texture.map(|idx| idx + texture_offset);
+            self.materials.push(material);
+        }
 
-        return scene;
+        self.textures.extend(textures);
     }
 }
 
@@ -95,29 +163,97 @@ pub struct Scene
     pub bvh_triangle_offsets: OpenCLBuffer<u32>,
     pub bvh_triangles: OpenCLBuffer<Triangle>,
 
+    // top level bvh over the objects, the leaves index into tlas_obj_ids
+    pub tlas_min_bounds: OpenCLBuffer<Float3>,
+    pub tlas_max_bounds: OpenCLBuffer<Float3>,
+    pub tlas_obj_counts: OpenCLBuffer<u32>,
+    pub tlas_left_firsts: OpenCLBuffer<u32>,
+    pub tlas_obj_ids: OpenCLBuffer<u32>,
+
     pub mesh_vertex_ids: OpenCLBuffer<Uint3>,
     pub mesh_vertex_normals: OpenCLBuffer<Float3>,
+    pub mesh_vertex_uvs: OpenCLBuffer<Float2>,
+    pub mesh_vertex_tangents: OpenCLBuffer<Float4>,
+    pub mesh_triangle_mat_ids: OpenCLBuffer<u32>,
 
     pub mat_offsets: OpenCLBuffer<u32>,
     pub mat_colors: OpenCLBuffer<u32>,
     pub mat_reflectiveness: OpenCLBuffer<u8>,
     pub mat_refraction_indices: OpenCLBuffer<f32>,
+    pub mat_emissions: OpenCLBuffer<Float3>,
+    pub mat_roughness: OpenCLBuffer<f32>,
+    pub mat_metallic: OpenCLBuffer<f32>,
+    pub mat_specular: OpenCLBuffer<f32>,
+    pub mat_albedo_textures: OpenCLBuffer<u32>,
+    pub mat_normal_textures: OpenCLBuffer<u32>,
+    pub mat_media: OpenCLBuffer<u32>,
+
+    // the media of closed meshes followed by the fog, a fog medium of u32::MAX means there is no fog
+    pub media: OpenCLBuffer<Medium>,
+    pub fog_medium: u32,
+    pub fog_min_bound: Float3,
+    pub fog_max_bound: Float3,
+    pub num_medium_boundaries: u32,
+
+    // all textures packed after each other
+    pub tex_offsets: OpenCLBuffer<u32>,
+    pub tex_dimensions: OpenCLBuffer<Uint2>,
+    pub tex_wrap_modes: OpenCLBuffer<u32>,
+    pub tex_pixels: OpenCLBuffer<u32>,
+
+    // emissive triangles in world space, three vertices per light
+    pub num_lights: u32,
+    pub light_total_power: f32,
+    pub light_vertices: OpenCLBuffer<Float3>,
+    pub light_emissions: OpenCLBuffer<Float3>,
+    pub light_areas: OpenCLBuffer<f32>,
+    pub light_cdf: OpenCLBuffer<f32>,
+
+    pub num_point_lights: u32,
+    pub point_lights: OpenCLBuffer<PointLight>,
+    pub num_spot_lights: u32,
+    pub spot_lights: OpenCLBuffer<SpotLight>,
+    pub num_directional_lights: u32,
+    pub directional_lights: OpenCLBuffer<DirectionalLight>,
+
+    // a width of 0 means there is no environment map
+    pub env_map_width: u32,
+    pub env_map_height: u32,
+    pub env_map_pixels: OpenCLBuffer<Float4>,
+    pub env_map_marginal_cdf: OpenCLBuffer<f32>,
+    pub env_map_conditional_cdf: OpenCLBuffer<f32>,
 
     pub bvhs: Vec<BVH>
 }
 
-impl Scene
+// opencl does not allow empty buffers, so empty lists are padded with a single unused value
+fn non_empty<T: Clone>(values: &[T], padding: T) -> Vec<T>
 {
-    pub fn new(cl: &OpenCL) -> Self
+    if values.is_empty()
     {
-        let scene = SceneDescription::new();
+        return vec![padding];
+    }
+    return values.to_vec();
+}
 
-        return Scene::from_scene_description(cl, &scene);
+impl Scene
+{
+    // the sample scene, lit by the environment map at environment_path or else by the sky gradient
+    pub fn new(cl: &OpenCL, environment_path: Option<&Path>) -> Self
+    {
+        let mut scene = SceneDescription::new();
+        if let Some(path) = environment_path
+        {
+            scene.set_environment_map(path);
+        }
+        return Scene::from_scene_description(cl, &scene, &scene.bvh_options);
     }
 
-    // create gpu scene based on scene
-    pub fn from_scene_description(cl: &OpenCL, scene: &SceneDescription) -> Self
+    // create gpu scene based on scene, the bvh of every mesh is built with the options at the index of the mesh
+    pub fn from_scene_description(cl: &OpenCL, scene: &SceneDescription, bvh_options: &[BVHBuildOptions]) -> Self
     {
+        assert_eq!(bvh_options.len(), scene.meshes.len(), "Every mesh needs its own bvh build options");
+
         let mut obj_mesh_ids: Vec<u32> = Vec::new();
         let mut obj_mat_ids: Vec<u32> = Vec::new();
         let mut obj_transforms: Vec<Mat4> = Vec::new();
@@ -135,11 +271,31 @@ impl Scene
 
         let mut mesh_vertex_ids: Vec<Uint3> = Vec::new();
         let mut mesh_vertex_normals: Vec<Float3> = Vec::new();
+        let mut mesh_vertex_uvs: Vec<Float2> = Vec::new();
+        let mut mesh_vertex_tangents: Vec<Float4> = Vec::new();
+        let mut mesh_triangle_mat_ids: Vec<u32> = Vec::new();
 
         let mut mat_offsets: Vec<u32> = Vec::new();
         let mut mat_colors: Vec<u32> = Vec::new();
         let mut mat_reflectiveness: Vec<u8> = Vec::new();
         let mut mat_refraction_indices: Vec<f32> = Vec::new();
+        let mut mat_emissions: Vec<Float3> = Vec::new();
+        let mut mat_roughness: Vec<f32> = Vec::new();
+        let mut mat_metallic: Vec<f32> = Vec::new();
+        let mut mat_specular: Vec<f32> = Vec::new();
+        let mut mat_albedo_textures: Vec<u32> = Vec::new();
+        let mut mat_normal_textures: Vec<u32> = Vec::new();
+        let mut mat_media: Vec<u32> = Vec::new();
+
+        let mut tex_offsets: Vec<u32> = Vec::new();
+        let mut tex_dimensions: Vec<Uint2> = Vec::new();
+        let mut tex_wrap_modes: Vec<u32> = Vec::new();
+        let mut tex_pixels: Vec<u32> = Vec::new();
+
+        let mut light_vertices: Vec<Float3> = Vec::new();
+        let mut light_emissions: Vec<Float3> = Vec::new();
+        let mut light_areas: Vec<f32> = Vec::new();
+        let mut light_cdf: Vec<f32> = Vec::new();
 
         for object in &scene.root_objects
         {
@@ -149,13 +305,39 @@ impl Scene
             obj_inv_transforms.push(object.inv_transform);
         }
 
-        let mut bvhs: Vec<BVH> = Vec::new();
+        // the meshes are built in parallel, collecting keeps them in the order of the meshes
+        let (bvhs, bvhs_from_cache): (Vec<BVH>, Vec<bool>) = match &scene.bvh_cache_dir
+        {
+            Some(cache_dir) =>
+            {
+                let (bvhs, cache_entries): (Vec<BVH>, Vec<(bool, u64)>) = scene.meshes.par_iter().enumerate().map(|(mesh_idx, mesh)|
+                {
+                    let (bvh, from_cache, key) = bvh_cache::load_or_build(cache_dir, &mesh.triangles, &bvh_options[mesh_idx]);
+                    return (bvh, (from_cache, key));
+                }).unzip();
+                let (bvhs_from_cache, keys): (Vec<bool>, Vec<u64>) = cache_entries.into_iter().unzip();
+                bvh_cache::prune(cache_dir, &keys);
+                (bvhs, bvhs_from_cache)
+            }
+            None =>
+            {
+                let bvhs: Vec<BVH> = scene.meshes.par_iter().enumerate().map(|(mesh_idx, mesh)|
+                {
+                    return BVH::from_mesh(&mesh.triangles, &compute_bounds_from_triangles(&mesh.triangles), &bvh_options[mesh_idx]);
+                }).collect();
+                let bvhs_from_cache = vec![false; bvhs.len()];
+                (bvhs, bvhs_from_cache)
+            }
+        };
+
         let mut bvh_offset = 0;
         let mut mesh_offset = 0;
         let mut triangle_offset = 0;
-        for mesh in &scene.meshes
+        let mut vertex_offset = 0;
+        for (mesh_idx, (mesh, bvh)) in scene.meshes.iter().zip(bvhs.iter()).enumerate()
         {
-            let bvh = BVH::from_mesh(&mesh.triangles, &compute_bounds_from_triangles(&mesh.triangles), 4);
+            let origin = if bvhs_from_cache[mesh_idx] { "loaded bvh from the cache" } else { "built bvh" };
+            info!("{} for mesh {} with {} triangles\n{}", origin, mesh_idx, mesh.triangles.len(), bvh.stats);
 
             bvh_offsets.push(bvh_offset);
             bvh_offset += bvh.bvh_nodes.len() as u32;
@@ -173,9 +355,10 @@ impl Scene
                 bvh_triangles.push(mesh.triangles[*id]);
             }
 
+            // vertex ids are stored globally so they can index the concatenated vertex buffers directly
             for ids in &mesh.triangle_vertex_ids
             {
-                mesh_vertex_ids.push(*ids);
+                mesh_vertex_ids.push(Uint3::from_xyz(ids.x + vertex_offset, ids.y + vertex_offset, ids.z + vertex_offset));
             }
 
             for normal in &mesh.vertex_normals
@@ -183,14 +366,41 @@ impl Scene
                 mesh_vertex_normals.push(*normal);
             }
 
+            // meshes without texture coordinates still need one per vertex to keep the vertex ids aligned
+            for vtx in 0..mesh.vertex_normals.len()
+            {
+                mesh_vertex_uvs.push(*mesh.vertex_uvs.get(vtx).unwrap_or(&Float2::zero()));
+                mesh_vertex_tangents.push(*mesh.vertex_tangents.get(vtx).unwrap_or(&Float4::from_xyzw(1.0, 0.0, 0.0, 1.0)));
+            }
+
+            vertex_offset += mesh.vertex_normals.len() as u32;
+
+            // indexed like the vertex ids, by the mesh offset and the index of the triangle
+            for tri in 0..mesh.triangle_vertex_ids.len()
+            {
+                mesh_triangle_mat_ids.push(*mesh.triangle_material_ids.get(tri).unwrap_or(&u32::MAX));
+            }
+
             mesh_offsets.push(mesh_offset);
             mesh_offset += mesh.triangle_vertex_ids.len() as u32;
 
             bvh_triangle_offsets.push(triangle_offset);
             triangle_offset += bvh.triangle_idx.len() as u32;
-            bvhs.push(bvh);
         }
 
+        // the objects are found through a bvh over their world space bounds
+        let object_bounds: Vec<AABB> = scene.root_objects.iter()
+            .map(|object| bvhs[object.mesh_idx as usize].bvh_nodes[0].bounds.transformed(&object.transform))
+            .collect();
+        let tlas = TLAS::from_bounds(&object_bounds);
+        info!("built tlas with {} nodes over {} objects", tlas.tlas_nodes.len(), object_bounds.len());
+
+        let tlas_min_bounds: Vec<Float3> = tlas.tlas_nodes.iter().map(|node| node.bounds.min_bound).collect();
+        let tlas_max_bounds: Vec<Float3> = tlas.tlas_nodes.iter().map(|node| node.bounds.max_bound).collect();
+        let tlas_obj_counts: Vec<u32> = tlas.tlas_nodes.iter().map(|node| node.tri_count as u32).collect();
+        let tlas_left_firsts: Vec<u32> = tlas.tlas_nodes.iter().map(|node| node.left_first as u32).collect();
+        let tlas_obj_ids: Vec<u32> = tlas.object_idx.iter().map(|idx| *idx as u32).collect();
+
         let mut mat_offset = 0;
         for material in &scene.materials
         {
@@ -210,8 +420,103 @@ impl Scene
             {
                 mat_refraction_indices.push(*refractive_index);
             }
+
+            mat_emissions.push(material.emitted_radiance());
+            mat_roughness.push(material.roughness.clamp(0.0, 1.0));
+            mat_metallic.push(material.metallic.clamp(0.0, 1.0));
+            mat_specular.push(material.specular.clamp(0.0, 1.0));
+            mat_albedo_textures.push(material.albedo_texture.unwrap_or(u32::MAX));
+            mat_normal_textures.push(material.normal_texture.unwrap_or(u32::MAX));
+            mat_media.push(material.medium.unwrap_or(u32::MAX));
+        }
+
+        // shadow rays only look for boundaries to pass through when there are any
+        let num_medium_boundaries = scene.materials.iter().filter(|material| material.is_medium_boundary()).count() as u32;
+
+        let mut media = scene.media.clone();
+        let (fog_medium, fog_min_bound, fog_max_bound) = match &scene.fog
+        {
+            Some(fog) =>
+            {
+                media.push(fog.medium);
+                (media.len() as u32 - 1, fog.bounds.min_bound, fog.bounds.max_bound)
+            },
+            None => (u32::MAX, Float3::zero(), Float3::zero())
+        };
+
+        let mut tex_offset = 0;
+        for texture in &scene.textures
+        {
+            tex_offsets.push(tex_offset);
+            tex_offset += texture.pixels.len() as u32;
+            tex_dimensions.push(Uint2::from_xy(texture.width, texture.height));
+            tex_wrap_modes.push(texture.wrap_mode as u32);
+            tex_pixels.extend_from_slice(&texture.pixels);
+        }
+
+        // every triangle of an emissive object becomes a light, lights are picked proportional to their power
+        let mut total_power = 0.0;
+        for object in &scene.root_objects
+        {
+            let mesh = &scene.meshes[object.mesh_idx as usize];
+            for triangle in &mesh.triangles
+            {
+                // triangles without a material of their own use the material of the object
+                let mat_idx = match mesh.triangle_material_ids.get(triangle.tri_idx as usize)
+                {
+                    Some(&idx) if idx != u32::MAX => idx,
+                    _ => object.mat_idx
+                };
+                let material = &scene.materials[mat_idx as usize];
+                if !material.is_emissive()
+                {
+                    continue;
+                }
+
+                let radiance = material.emitted_radiance();
+                let vertex0 = transform_position(&triangle.vertex0, &object.transform);
+                let vertex1 = transform_position(&triangle.vertex1, &object.transform);
+                let vertex2 = transform_position(&triangle.vertex2, &object.transform);
+
+                let area = length(&cross(&(vertex1 - vertex0), &(vertex2 - vertex0))) * 0.5;
+                if area <= 0.0
+                {
+                    continue;
+                }
+
+                light_vertices.push(vertex0);
+                light_vertices.push(vertex1);
+                light_vertices.push(vertex2);
+                light_emissions.push(radiance);
+                light_areas.push(area);
+
+                // the surfaces emit on both sides
+                total_power += luminance(&radiance) * area * PI * 2.0;
+                light_cdf.push(total_power);
+            }
         }
 
+        for cdf in &mut light_cdf
+        {
+            *cdf /= total_power;
+        }
+
+        info!("collected {} emissive triangles", light_areas.len());
+
+        let num_lights = if total_power > 0.0 { light_areas.len() as u32 } else { 0 };
+        let light_total_power = total_power;
+        if light_areas.is_empty()
+        {
+            light_vertices = vec![Float3::zero(); 3];
+        }
+        let light_emissions = non_empty(&light_emissions, Float3::zero());
+        let light_areas = non_empty(&light_areas, 0.0);
+        let light_cdf = non_empty(&light_cdf, 1.0);
+
+        let num_point_lights = scene.point_lights.len() as u32;
+        let num_spot_lights = scene.spot_lights.len() as u32;
+        let num_directional_lights = scene.directional_lights.len() as u32;
+        info!("uploading {} point, {} spot and {} directional lights", num_point_lights, num_spot_lights, num_directional_lights);
 
         let obj_mesh_ids = OpenCLBuffer::read_write(cl, obj_mesh_ids);
         let obj_mat_ids = OpenCLBuffer::read_write(cl, obj_mat_ids);
@@ -225,12 +530,56 @@ impl Scene
         let bvh_left_firsts = OpenCLBuffer::read_write(cl, bvh_left_firsts);
         let bvh_triangle_offsets = OpenCLBuffer::read_write(cl, bvh_triangle_offsets);
         let bvh_triangles = OpenCLBuffer::read_write(cl, bvh_triangles);
+        let tlas_min_bounds = OpenCLBuffer::read_write(cl, tlas_min_bounds);
+        let tlas_max_bounds = OpenCLBuffer::read_write(cl, tlas_max_bounds);
+        let tlas_obj_counts = OpenCLBuffer::read_write(cl, tlas_obj_counts);
+        let tlas_left_firsts = OpenCLBuffer::read_write(cl, tlas_left_firsts);
+        let tlas_obj_ids = OpenCLBuffer::read_write(cl, non_empty(&tlas_obj_ids, 0));
         let mesh_vertex_ids = OpenCLBuffer::read_write(cl, mesh_vertex_ids);
         let mesh_vertex_normals = OpenCLBuffer::read_write(cl, mesh_vertex_normals);
+        let mesh_vertex_uvs = OpenCLBuffer::read_write(cl, mesh_vertex_uvs);
+        let mesh_vertex_tangents = OpenCLBuffer::read_write(cl, mesh_vertex_tangents);
+        let mesh_triangle_mat_ids = OpenCLBuffer::read_write(cl, mesh_triangle_mat_ids);
         let mat_offsets = OpenCLBuffer::read_write(cl, mat_offsets);
         let mat_colors = OpenCLBuffer::read_write(cl, mat_colors);
         let mat_reflectiveness = OpenCLBuffer::read_write(cl, mat_reflectiveness);
         let mat_refraction_indices = OpenCLBuffer::read_write(cl, mat_refraction_indices);
+        let mat_emissions = OpenCLBuffer::read_write(cl, mat_emissions);
+        let mat_roughness = OpenCLBuffer::read_write(cl, mat_roughness);
+        let mat_metallic = OpenCLBuffer::read_write(cl, mat_metallic);
+        let mat_specular = OpenCLBuffer::read_write(cl, mat_specular);
+        let mat_albedo_textures = OpenCLBuffer::read_write(cl, mat_albedo_textures);
+        let mat_normal_textures = OpenCLBuffer::read_write(cl, mat_normal_textures);
+        let mat_media = OpenCLBuffer::read_write(cl, mat_media);
+        let media = OpenCLBuffer::read_write(cl, non_empty(&media, Medium::new(Float3::zero(), Float3::zero(), 0.0)));
+        let tex_offsets = OpenCLBuffer::read_write(cl, non_empty(&tex_offsets, 0));
+        let tex_dimensions = OpenCLBuffer::read_write(cl, non_empty(&tex_dimensions, Uint2::from_xy(1, 1)));
+        let tex_wrap_modes = OpenCLBuffer::read_write(cl, non_empty(&tex_wrap_modes, 0));
+        let tex_pixels = OpenCLBuffer::read_write(cl, non_empty(&tex_pixels, 0));
+        let light_vertices = OpenCLBuffer::read_write(cl, light_vertices);
+        let light_emissions = OpenCLBuffer::read_write(cl, light_emissions);
+        let light_areas = OpenCLBuffer::read_write(cl, light_areas);
+        let light_cdf = OpenCLBuffer::read_write(cl, light_cdf);
+        let point_lights = OpenCLBuffer::read_write(cl, non_empty(&scene.point_lights, PointLight::new(Float3::zero(), Float3::zero(), 0.0, 0.0)));
+        let spot_lights = OpenCLBuffer::read_write(cl, non_empty(&scene.spot_lights, SpotLight::new(Float3::zero(), Float3::from_xyz(0.0, -1.0, 0.0), Float3::zero(), 0.0, 0.0, 0.0, 0.0)));
+        let (env_map_width, env_map_height, env_map_pixels, env_map_marginal_cdf, env_map_conditional_cdf) = match &scene.environment_map
+        {
+            Some(environment_map) => (
+                environment_map.width,
+                environment_map.height,
+                OpenCLBuffer::read_write(cl, environment_map.pixels.clone()),
+                OpenCLBuffer::read_write(cl, environment_map.marginal_cdf.clone()),
+                OpenCLBuffer::read_write(cl, environment_map.conditional_cdf.clone())
+            ),
+            None => (
+                0,
+                0,
+                OpenCLBuffer::read_write(cl, vec![Float4::zero()]),
+                OpenCLBuffer::read_write(cl, vec![1.0]),
+                OpenCLBuffer::read_write(cl, vec![1.0])
+            )
+        };
+        let directional_lights = OpenCLBuffer::read_write(cl, non_empty(&scene.directional_lights, DirectionalLight::new(Float3::from_xyz(0.0, -1.0, 0.0), Float3::zero(), 0.0, 0.0)));
 
         obj_mesh_ids.copy_to_device(cl);
         obj_mat_ids.copy_to_device(cl);
@@ -244,12 +593,42 @@ impl Scene
         bvh_left_firsts.copy_to_device(cl);
         bvh_triangle_offsets.copy_to_device(cl);
         bvh_triangles.copy_to_device(cl);
+        tlas_min_bounds.copy_to_device(cl);
+        tlas_max_bounds.copy_to_device(cl);
+        tlas_obj_counts.copy_to_device(cl);
+        tlas_left_firsts.copy_to_device(cl);
+        tlas_obj_ids.copy_to_device(cl);
         mesh_vertex_ids.copy_to_device(cl);
         mesh_vertex_normals.copy_to_device(cl);
+        mesh_vertex_uvs.copy_to_device(cl);
+        mesh_vertex_tangents.copy_to_device(cl);
+        mesh_triangle_mat_ids.copy_to_device(cl);
         mat_offsets.copy_to_device(cl);
         mat_colors.copy_to_device(cl);
         mat_reflectiveness.copy_to_device(cl);
         mat_refraction_indices.copy_to_device(cl);
+        mat_emissions.copy_to_device(cl);
+        mat_roughness.copy_to_device(cl);
+        mat_metallic.copy_to_device(cl);
+        mat_specular.copy_to_device(cl);
+        mat_albedo_textures.copy_to_device(cl);
+        mat_normal_textures.copy_to_device(cl);
+        mat_media.copy_to_device(cl);
+        media.copy_to_device(cl);
+        tex_offsets.copy_to_device(cl);
+        tex_dimensions.copy_to_device(cl);
+        tex_wrap_modes.copy_to_device(cl);
+        tex_pixels.copy_to_device(cl);
+        light_vertices.copy_to_device(cl);
+        light_emissions.copy_to_device(cl);
+        light_areas.copy_to_device(cl);
+        light_cdf.copy_to_device(cl);
+        point_lights.copy_to_device(cl);
+        spot_lights.copy_to_device(cl);
+        directional_lights.copy_to_device(cl);
+        env_map_pixels.copy_to_device(cl);
+        env_map_marginal_cdf.copy_to_device(cl);
+        env_map_conditional_cdf.copy_to_device(cl);
 
         return Scene
         {
@@ -265,12 +644,53 @@ impl Scene
             bvh_left_firsts,
             bvh_triangle_offsets,
             bvh_triangles,
+            tlas_min_bounds,
+            tlas_max_bounds,
+            tlas_obj_counts,
+            tlas_left_firsts,
+            tlas_obj_ids,
             mesh_vertex_ids,
             mesh_vertex_normals,
+            mesh_vertex_uvs,
+            mesh_vertex_tangents,
+            mesh_triangle_mat_ids,
             mat_offsets,
             mat_colors,
             mat_reflectiveness,
             mat_refraction_indices,
+            mat_emissions,
+            mat_roughness,
+            mat_metallic,
+            mat_specular,
+            mat_albedo_textures,
+            mat_normal_textures,
+            mat_media,
+            media,
+            fog_medium,
+            fog_min_bound,
+            fog_max_bound,
+            num_medium_boundaries,
+            tex_offsets,
+            tex_dimensions,
+            tex_wrap_modes,
+            tex_pixels,
+            num_lights,
+            light_total_power,
+            light_vertices,
+            light_emissions,
+            light_areas,
+            light_cdf,
+            num_point_lights,
+            point_lights,
+            num_spot_lights,
+            spot_lights,
+            num_directional_lights,
+            directional_lights,
+            env_map_width,
+            env_map_height,
+            env_map_pixels,
+            env_map_marginal_cdf,
+            env_map_conditional_cdf,
             bvhs
         }
     }
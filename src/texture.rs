@@ -0,0 +1,42 @@
+use log::info;
+use image::GenericImageView;
+
+// how texture coordinates outside of [0, 1] are mapped, keep in sync with the defines in texture.cl
+#[derive(PartialEq, Copy, Clone)]
+pub enum WrapMode
+{
+    Repeat = 0,
+    Clamp = 1
+}
+
+// sRGB encoded texture, every pixel is packed as 0xAARRGGBB
+pub struct Texture
+{
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u32>,
+    pub wrap_mode: WrapMode
+}
+
+impl Texture
+{
+    pub fn from_file(path: &std::path::Path, wrap_mode: WrapMode) -> Self
+    {
+        info!("loading texture {}", path.display());
+
+        let img = image::open(path).expect("Texture not found");
+
+        let mut pixels: Vec<u32> = Vec::with_capacity((img.width() * img.height()) as usize);
+        for (_, _, value) in img.pixels()
+        {
+            pixels.push(((value[3] as u32) << 24) + ((value[0] as u32) << 16) + ((value[1] as u32) << 8) + value[2] as u32);
+        }
+
+        return Texture{
+            width: img.width(),
+            height: img.height(),
+            pixels,
+            wrap_mode
+        };
+    }
+}
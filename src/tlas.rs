@@ -0,0 +1,172 @@
+use crate::math::*;
+use crate::render_components::{AABB, BVHNode};
+
+// the object centroids are sorted into this many bins per axis when looking for a split
+const TLAS_BINS: usize = 8;
+
+// top level bvh over the world space bounds of the scene objects,
+// the leaves hold indices into the objects instead of triangles
+pub struct TLAS
+{
+    pub tlas_nodes: Vec<BVHNode>,
+    pub object_idx: Vec<usize>
+}
+
+impl TLAS
+{
+    pub fn from_bounds(object_bounds: &Vec<AABB>) -> Self
+    {
+        let mut root = BVHNode{
+            bounds: AABB::from_empty(),
+            tri_count: object_bounds.len(),
+            left_first: 0
+        };
+        for bounds in object_bounds
+        {
+            root.bounds.grow_aabb(bounds);
+        }
+
+        let mut tlas = TLAS{
+            tlas_nodes: Vec::with_capacity(object_bounds.len() * 2),
+            object_idx: (0..object_bounds.len()).collect()
+        };
+        tlas.tlas_nodes.push(root);
+        tlas.subdivide(0, object_bounds);
+
+        return tlas;
+    }
+
+    fn centroid(bounds: &AABB) -> Float3
+    {
+        return (bounds.min_bound + bounds.max_bound) * 0.5;
+    }
+
+    fn leaf_bounds(&self, first: usize, count: usize, object_bounds: &Vec<AABB>) -> AABB
+    {
+        let mut bounds = AABB::from_empty();
+        for idx in &self.object_idx[first..first + count]
+        {
+            bounds.grow_aabb(&object_bounds[*idx]);
+        }
+        return bounds;
+    }
+
+    // binned surface area heuristic over the centroids, nodes are only split when that is cheaper than a leaf
+    fn subdivide(&mut self, node_idx: usize, object_bounds: &Vec<AABB>)
+    {
+        let node = self.tlas_nodes[node_idx];
+        let first = node.left_first;
+        let count = node.tri_count;
+        if count <= 1
+        {
+            return;
+        }
+
+        let mut centroid_bounds = AABB::from_empty();
+        for idx in &self.object_idx[first..first + count]
+        {
+            centroid_bounds.grow(&TLAS::centroid(&object_bounds[*idx]));
+        }
+
+        let mut best_cost = count as f32 * node.bounds.area();
+        let mut best_axis = usize::MAX;
+        let mut best_split = 0.0;
+        for axis in 0..3
+        {
+            let min_centroid = centroid_bounds.minimal(axis);
+            let extent = centroid_bounds.maximal(axis) - min_centroid;
+            if extent <= 0.0
+            {
+                continue;
+            }
+
+            let mut bin_bounds = [AABB::from_empty(); TLAS_BINS];
+            let mut bin_counts = [0usize; TLAS_BINS];
+            for idx in &self.object_idx[first..first + count]
+            {
+                let bounds = &object_bounds[*idx];
+                let bin = (((bounds.center(axis) - min_centroid) / extent * TLAS_BINS as f32) as usize).min(TLAS_BINS - 1);
+                bin_bounds[bin].grow_aabb(bounds);
+                bin_counts[bin] += 1;
+            }
+
+            // sweep from both sides to get the cost of every split between two bins
+            let mut left_areas = [0.0; TLAS_BINS - 1];
+            let mut left_counts = [0usize; TLAS_BINS - 1];
+            let mut left_bounds = AABB::from_empty();
+            let mut left_count = 0;
+            for bin in 0..TLAS_BINS - 1
+            {
+                left_bounds.grow_aabb(&bin_bounds[bin]);
+                left_count += bin_counts[bin];
+                left_areas[bin] = left_bounds.area();
+                left_counts[bin] = left_count;
+            }
+
+            let mut right_bounds = AABB::from_empty();
+            let mut right_count = 0;
+            for bin in (1..TLAS_BINS).rev()
+            {
+                right_bounds.grow_aabb(&bin_bounds[bin]);
+                right_count += bin_counts[bin];
+                if left_counts[bin - 1] == 0 || right_count == 0
+                {
+                    continue;
+                }
+
+                let cost = left_counts[bin - 1] as f32 * left_areas[bin - 1] + right_count as f32 * right_bounds.area();
+                if cost < best_cost
+                {
+                    best_cost = cost;
+                    best_axis = axis;
+                    best_split = min_centroid + extent * bin as f32 / TLAS_BINS as f32;
+                }
+            }
+        }
+
+        if best_axis == usize::MAX
+        {
+            return;
+        }
+
+        // partition the objects around the split plane
+        let mut i = first;
+        let mut j = first + count;
+        while i < j
+        {
+            if object_bounds[self.object_idx[i]].center(best_axis) < best_split
+            {
+                i += 1;
+            }
+            else
+            {
+                j -= 1;
+                self.object_idx.swap(i, j);
+            }
+        }
+
+        let left_count = i - first;
+        if left_count == 0 || left_count == count
+        {
+            return;
+        }
+
+        // the children are stored next to each other, the left child is found through left_first
+        let left_idx = self.tlas_nodes.len();
+        self.tlas_nodes.push(BVHNode{
+            bounds: self.leaf_bounds(first, left_count, object_bounds),
+            tri_count: left_count,
+            left_first: first
+        });
+        self.tlas_nodes.push(BVHNode{
+            bounds: self.leaf_bounds(i, count - left_count, object_bounds),
+            tri_count: count - left_count,
+            left_first: i
+        });
+        self.tlas_nodes[node_idx].left_first = left_idx;
+        self.tlas_nodes[node_idx].tri_count = 0;
+
+        self.subdivide(left_idx, object_bounds);
+        self.subdivide(left_idx + 1, object_bounds);
+    }
+}